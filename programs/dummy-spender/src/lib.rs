@@ -23,6 +23,20 @@ pub mod dummy_spender {
 
         Ok(())
     }
+
+    /// Write data into an account owned by this program.
+    /// Used by the tests to forge accounts that mimic those of other programs.
+    pub fn write_data(ctx: Context<WriteData>, offset: u32, data: Vec<u8>) -> ProgramResult {
+        let mut account_data = ctx.accounts.account.try_borrow_mut_data()?;
+        let start = offset as usize;
+        let end = start
+            .checked_add(data.len())
+            .filter(|end| *end <= account_data.len())
+            .ok_or(ErrorCode::DataTooLarge)?;
+        account_data[start..end].copy_from_slice(&data);
+
+        Ok(())
+    }
 }
 
 #[derive(Accounts)]
@@ -37,8 +51,16 @@ pub struct Spend<'info> {
     token_program: AccountInfo<'info>,
 }
 
+#[derive(Accounts)]
+pub struct WriteData<'info> {
+    #[account(mut)]
+    account: AccountInfo<'info>,
+}
+
 #[error]
 pub enum ErrorCode {
     #[msg("Token transfer failed")]
     TokenTransferFailed,
+    #[msg("The data does not fit in the account")]
+    DataTooLarge,
 }
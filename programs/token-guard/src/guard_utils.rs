@@ -1,15 +1,23 @@
 use {
     crate::{
         id,
-        nft_utils::check_nft_metadata,
-        token_utils::{assert_initialized, assert_owned_by},
-        AllowanceAccount, ErrorCode, Strategy, TokenGuard, ALLOWANCE_ACCOUNT_SEED,
+        nft_utils::{check_master_edition, check_nft_metadata},
+        token_utils::{
+            assert_initialized, assert_owned_by, spl_token_burn, spl_token_transfer,
+            TokenBurnParams, TokenTransferParams,
+        },
+        AllowanceAccount, ErrorCode, GuardProperties, MembershipRequirement, Phase, RecipientShare,
+        Strategy, TokenGuard, ALLOWANCE_ACCOUNT_SEED, ALLOWANCE_ACCOUNT_SIZE,
+        MAX_MEMBERSHIP_ALTERNATIVES, MAX_MEMBERSHIP_REQUIREMENTS, MAX_PHASES, MAX_RECIPIENTS,
+        MINT_AUTHORITY_SEED, TOTAL_BPS, VAULT_AUTHORITY_SEED,
     },
     anchor_lang::{
         prelude::*,
         solana_program::program::invoke,
         solana_program::{program::invoke_signed, program_option::COption, system_instruction},
     },
+    anchor_spl::associated_token::get_associated_token_address,
+    metaplex_token_metadata::state::MAX_CREATOR_LIMIT,
     solana_gateway::Gateway,
     spl_token::state::Mint,
     std::borrow::BorrowMut,
@@ -37,11 +45,138 @@ pub fn check_out_mint(out_mint: &AccountInfo, mint_authority: &AccountInfo) -> P
     Ok(())
 }
 
-pub fn check_start_time(
-    clock: &Sysvar<Clock>,
+pub fn check_pending_authority(
+    pending_authority: &Signer,
     token_guard: &ProgramAccount<TokenGuard>,
 ) -> ProgramResult {
-    if let Some(start_time) = token_guard.start_time {
+    if token_guard.pending_authority != Some(*pending_authority.key) {
+        msg!("{} is not the pending authority", pending_authority.key);
+        return Err(ErrorCode::PendingAuthorityMismatch.into());
+    }
+
+    Ok(())
+}
+
+pub fn check_not_paused(token_guard: &ProgramAccount<TokenGuard>) -> ProgramResult {
+    if token_guard.is_paused {
+        msg!("Paused");
+        return Err(ErrorCode::Paused.into());
+    }
+
+    Ok(())
+}
+
+/// Returns the index and properties of the phase that is live at the clock time.
+/// If no phase is live, returns the next phase to start, or failing that, the last phase,
+/// so that check_start_time and check_end_time report why the exchange is not allowed.
+/// If the token guard has no phases, its top-level properties are returned as a single phase.
+pub fn get_active_phase(
+    clock: &Sysvar<Clock>,
+    token_guard: &ProgramAccount<TokenGuard>,
+) -> (Option<u8>, Phase) {
+    if token_guard.phases.is_empty() {
+        let phase = Phase {
+            start_time: token_guard.start_time,
+            end_time: token_guard.end_time,
+            membership: token_guard.membership.clone(),
+            allowance: token_guard.allowance,
+            max_amount: token_guard.max_amount,
+            min_amount: token_guard.min_amount,
+            fixed_price: token_guard.fixed_price,
+        };
+        return (None, phase);
+    }
+
+    let now = clock.unix_timestamp;
+    let has_started = |phase: &Phase| phase.start_time.map_or(true, |t| now >= t);
+    let has_ended = |phase: &Phase| phase.end_time.map_or(false, |t| now >= t);
+
+    let phases = &token_guard.phases;
+    let index = phases
+        .iter()
+        .position(|phase| has_started(phase) && !has_ended(phase))
+        .or_else(|| phases.iter().position(|phase| !has_started(phase)))
+        .unwrap_or(phases.len() - 1);
+
+    msg!("Phase {} is active", index);
+    (Some(index as u8), phases[index].clone())
+}
+
+/// The number of remaining accounts used to present a membership token with the strategy
+pub fn membership_accounts_len(requirement: &MembershipRequirement) -> usize {
+    let len = match requirement.strategy {
+        Strategy::GatewayOnly => return 0,
+        // the token account, and the mint if the token is burned
+        Strategy::MembershipSPLToken if requirement.burn_membership_token => 2,
+        Strategy::MembershipSPLToken => 1,
+        // the token account, the mint, the metadata account and the master edition account
+        _ if requirement.require_master_edition => 4,
+        // the token account, the mint and the metadata account
+        _ => 3,
+    };
+
+    // the vault that the membership token is collected in is passed last
+    if requirement.collect_membership_token {
+        len + 1
+    } else {
+        len
+    }
+}
+
+/// The membership requirements of the phase, each as a list of options, any one of which meets it.
+/// The first is the phase's own membership token, if any, or one of the membership alternatives
+/// of the token guard. It is followed by the membership requirements of the token guard,
+/// which apply in every phase
+pub fn get_membership_requirements(
+    phase: &Phase,
+    token_guard: &ProgramAccount<TokenGuard>,
+) -> Vec<Vec<MembershipRequirement>> {
+    let phase_requirement = phase.membership.clone();
+
+    // the alternatives only replace a membership token, so do not apply to gateway-only phases
+    let phase_options = match phase_requirement.strategy {
+        Strategy::GatewayOnly => vec![phase_requirement],
+        _ => std::iter::once(phase_requirement)
+            .chain(token_guard.membership_alternatives.iter().cloned())
+            .collect(),
+    };
+
+    std::iter::once(phase_options)
+        .chain(
+            token_guard
+                .membership_requirements
+                .iter()
+                .map(|requirement| vec![requirement.clone()]),
+        )
+        .collect()
+}
+
+/// Split the remaining accounts into a group of membership token accounts for each requirement,
+/// and the payment recipients.
+/// Each group has room for the accounts of the option needing the most accounts
+pub fn split_remaining_accounts<'a, 'info>(
+    remaining_accounts: &'a [AccountInfo<'info>],
+    requirements: &[Vec<MembershipRequirement>],
+) -> (Vec<&'a [AccountInfo<'info>]>, &'a [AccountInfo<'info>]) {
+    let mut rest = remaining_accounts;
+    let mut groups = Vec::with_capacity(requirements.len());
+    for options in requirements {
+        let len = options
+            .iter()
+            .map(membership_accounts_len)
+            .max()
+            .unwrap_or(0)
+            .min(rest.len());
+        let (group, remaining) = rest.split_at(len);
+        groups.push(group);
+        rest = remaining;
+    }
+
+    (groups, rest)
+}
+
+pub fn check_start_time(clock: &Sysvar<Clock>, phase: &Phase) -> ProgramResult {
+    if let Some(start_time) = phase.start_time {
         if clock.unix_timestamp < start_time {
             msg!("Not live yet");
             return Err(ErrorCode::NotLiveYet.into());
@@ -51,9 +186,48 @@ pub fn check_start_time(
     Ok(())
 }
 
-pub fn check_max_amount(lamports: u64, token_guard: &ProgramAccount<TokenGuard>) -> ProgramResult {
-    if let Some(max_amount) = token_guard.max_amount {
-        if lamports > max_amount {
+pub fn check_end_time(clock: &Sysvar<Clock>, phase: &Phase) -> ProgramResult {
+    if let Some(end_time) = phase.end_time {
+        if clock.unix_timestamp >= end_time {
+            msg!("Ended");
+            return Err(ErrorCode::Ended.into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the amount to be paid: the fixed price, if the phase has one
+/// (in which case the amount may be omitted), or else the amount passed in
+pub fn get_amount(amount: Option<u64>, phase: &Phase) -> Result<u64, ProgramError> {
+    match (phase.fixed_price, amount) {
+        (Some(fixed_price), None) => Ok(fixed_price),
+        (Some(fixed_price), Some(amount)) => {
+            if amount != fixed_price {
+                msg!("Amount {} does not equal price {}", amount, fixed_price);
+                return Err(ErrorCode::IncorrectPrice.into());
+            }
+            Ok(amount)
+        }
+        (None, Some(amount)) => Ok(amount),
+        (None, None) => Err(ErrorCode::MissingAmount.into()),
+    }
+}
+
+pub fn check_min_amount(amount: u64, phase: &Phase) -> ProgramResult {
+    if let Some(min_amount) = phase.min_amount {
+        if amount < min_amount {
+            msg!("Amount is below minimum");
+            return Err(ErrorCode::MinAmountNotReached.into());
+        }
+    }
+
+    Ok(())
+}
+
+pub fn check_max_amount(amount: u64, phase: &Phase) -> ProgramResult {
+    if let Some(max_amount) = phase.max_amount {
+        if amount > max_amount {
             msg!("Amount exceeds maximum");
             return Err(ErrorCode::MaxAmountExceeded.into());
         }
@@ -84,8 +258,31 @@ pub fn check_gateway_token(
     Ok(())
 }
 
+pub fn check_mint_authority_account(
+    mint_authority: &AccountInfo,
+    token_guard: &ProgramAccount<TokenGuard>,
+) -> ProgramResult {
+    // should match deriveMintAuthority in the client
+    let expected_mint_authority = Pubkey::create_program_address(
+        &[
+            &MINT_AUTHORITY_SEED[..],
+            &token_guard.key().to_bytes(),
+            &[token_guard.mint_authority_bump],
+        ],
+        &id(),
+    )
+    .map_err(|_| ErrorCode::MintAuthorityMismatch)?;
+    if expected_mint_authority != *mint_authority.key {
+        msg!("Expected mint authority {}", expected_mint_authority);
+        return Err(ErrorCode::MintAuthorityMismatch.into());
+    }
+
+    Ok(())
+}
+
 pub fn check_payer_token_account(
     payer_ata: &AccountInfo,
+    payer: &Signer,
     token_guard: &ProgramAccount<TokenGuard>,
 ) -> ProgramResult {
     // is the payer's token account for the correct mint?
@@ -93,6 +290,11 @@ pub fn check_payer_token_account(
     if token_account.mint != token_guard.out_mint {
         return Err(ErrorCode::MintMismatch.into());
     }
+    // are the out tokens minted to the payer?
+    if token_account.owner != *payer.key {
+        msg!("Payer token account owner {}", token_account.owner);
+        return Err(ErrorCode::TokenAccountOwnerMismatch.into());
+    }
     // Is the payer's token account ephemeral?
     msg!("Checking token account is ephemeral");
     if payer_ata.lamports() != 0 {
@@ -106,10 +308,31 @@ pub fn check_payer_token_account(
     Ok(())
 }
 
-pub fn check_balance(lamports: u64, payer: &Signer) -> ProgramResult {
+pub fn check_balance(
+    amount: u64,
+    payer: &Signer,
+    payer_in_token_account: &AccountInfo,
+    token_guard: &ProgramAccount<TokenGuard>,
+) -> ProgramResult {
     // Does the payer have enough funds?
-    if payer.lamports() < lamports {
-        return Err(ErrorCode::NotEnoughSOL.into());
+    match token_guard.in_mint {
+        None => {
+            if payer.lamports() < amount {
+                return Err(ErrorCode::NotEnoughSOL.into());
+            }
+        }
+        Some(in_mint) => {
+            assert_owned_by(payer_in_token_account, &spl_token::id())?;
+            let token_account: spl_token::state::Account =
+                assert_initialized(payer_in_token_account)?;
+            if token_account.mint != in_mint {
+                msg!("Payer token account is not for mint {}", in_mint);
+                return Err(ErrorCode::MintMismatch.into());
+            }
+            if token_account.amount < amount {
+                return Err(ErrorCode::NotEnoughTokens.into());
+            }
+        }
     }
 
     Ok(())
@@ -119,55 +342,295 @@ pub fn check_membership_token(
     optional_membership_token: &Option<&AccountInfo>,
     optional_membership_token_mint: &Option<&AccountInfo>,
     optional_metadata_account: &Option<&AccountInfo>,
-    token_guard: &ProgramAccount<TokenGuard>,
+    optional_master_edition_account: &Option<&AccountInfo>,
+    payer: &Pubkey,
+    requirement: &MembershipRequirement,
 ) -> ProgramResult {
     msg!(
         "Checking membership token with strategy {:?}",
-        token_guard.strategy
+        requirement.strategy
     );
-    match token_guard.strategy {
+    match requirement.strategy {
         Strategy::GatewayOnly => { /* No membership token - do nothing */ }
         Strategy::MembershipSPLToken => {
             let membership_token = optional_membership_token.ok_or(ErrorCode::NoMembershipToken)?;
+            // the owner and balance are read from the account data,
+            // which any program could forge for an account it owns
+            assert_owned_by(membership_token, &spl_token::id())?;
             let token_account: spl_token::state::Account = assert_initialized(&membership_token)?;
-            if token_account.mint != token_guard.membership_token.unwrap() {
+            check_membership_token_owner(&token_account, payer, requirement)?;
+            let membership_token_mint = requirement
+                .membership_token
+                .ok_or(ErrorCode::InvalidStrategy)?;
+            if token_account.mint != membership_token_mint {
                 return Err(ErrorCode::MembershipTokenMismatch.into());
             }
-            if token_account.amount == 0 {
+            let presented_amount = get_presented_amount(&token_account, payer);
+            let min_membership_balance = requirement.min_membership_balance.max(1);
+            if presented_amount < min_membership_balance {
+                msg!(
+                    "Membership token balance {} is below the required {}",
+                    presented_amount,
+                    min_membership_balance
+                );
                 return Err(ErrorCode::NoMembershipToken.into());
             }
         }
         _ => {
             let membership_token = optional_membership_token.ok_or(ErrorCode::NoMembershipToken)?;
+            assert_owned_by(membership_token, &spl_token::id())?;
             let token_account: spl_token::state::Account = assert_initialized(&membership_token)?;
+            check_membership_token_owner(&token_account, payer, requirement)?;
             if token_account.amount == 0 {
                 return Err(ErrorCode::NoMembershipToken.into());
             }
 
             let membership_token_mint =
                 optional_membership_token_mint.ok_or(ErrorCode::MembershipTokenMismatch)?;
-            // need to set the output variable here so the compiler can check it has the right traits
-            // TODO a rustier way of doing this?
-            let _mint: Mint = assert_initialized(membership_token_mint)?;
+            let mint: Mint = assert_initialized(membership_token_mint)?;
             assert_owned_by(membership_token_mint, &spl_token::id())?;
 
             if token_account.mint != *membership_token_mint.key {
                 return Err(ErrorCode::MintMismatch.into());
             }
 
+            // a fungible token with metadata is not an NFT,
+            // and could be used many times with an allowance keyed on the mint
+            if mint.decimals != 0 {
+                msg!("Membership token mint has {} decimals", mint.decimals);
+                return Err(ErrorCode::InvalidNftDecimals.into());
+            }
+            if mint.supply != 1 {
+                msg!("Membership token mint has a supply of {}", mint.supply);
+                return Err(ErrorCode::InvalidNftSupply.into());
+            }
+
             let metadata_account =
                 optional_metadata_account.ok_or(ErrorCode::MembershipTokenMismatch)?;
 
-            check_nft_metadata(metadata_account, membership_token_mint, token_guard)?
+            check_nft_metadata(metadata_account, membership_token_mint, requirement)?;
+
+            if requirement.require_master_edition {
+                let master_edition_account =
+                    optional_master_edition_account.ok_or(ErrorCode::NoMasterEdition)?;
+                check_master_edition(master_edition_account, membership_token_mint)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check the membership token accounts against each option of a membership requirement,
+/// returning the first option that is met, or the error of the first option if none are.
+pub fn check_any_membership_token<'a>(
+    membership_accounts: &[AccountInfo],
+    payer: &Pubkey,
+    options: &'a [MembershipRequirement],
+) -> Result<&'a MembershipRequirement, ProgramError> {
+    let mut first_error = None;
+    for option in options {
+        match check_membership_token(
+            &membership_accounts.get(0),
+            &membership_accounts.get(1),
+            &membership_accounts.get(2),
+            &membership_accounts.get(3),
+            payer,
+            option,
+        ) {
+            Ok(()) => return Ok(option),
+            Err(error) => {
+                first_error.get_or_insert(error);
+            }
+        }
+    }
+
+    Err(first_error.unwrap_or_else(|| ErrorCode::NoMembershipToken.into()))
+}
+
+/// Burn the presented membership token (or the configured amount of it),
+/// with the payer as the owner or delegate of the token account
+pub fn burn_membership_token<'info>(
+    membership_accounts: &[AccountInfo<'info>],
+    payer: &Signer<'info>,
+    requirement: &MembershipRequirement,
+    token_program: &AccountInfo<'info>,
+) -> ProgramResult {
+    let membership_token = membership_accounts
+        .get(0)
+        .ok_or(ErrorCode::NoMembershipToken)?;
+    let membership_token_mint = membership_accounts
+        .get(1)
+        .ok_or(ErrorCode::MembershipTokenMismatch)?;
+    let token_account: spl_token::state::Account = assert_initialized(membership_token)?;
+    if token_account.mint != *membership_token_mint.key {
+        return Err(ErrorCode::MintMismatch.into());
+    }
+
+    let amount = get_consumed_amount(&token_account, payer.key, requirement);
+
+    msg!(
+        "Burning {} of membership token {}",
+        amount,
+        membership_token_mint.key
+    );
+    spl_token_burn(TokenBurnParams {
+        source: membership_token.clone(),
+        mint: membership_token_mint.clone(),
+        amount,
+        authority: payer.to_account_info(),
+        authority_signer_seeds: &[],
+        token_program: token_program.clone(),
+    })
+}
+
+/// Transfer the presented membership token (or the configured amount of it)
+/// into the token guard's vault for its mint, from which the authority can withdraw it
+pub fn collect_membership_token<'info>(
+    membership_accounts: &[AccountInfo<'info>],
+    payer: &Signer<'info>,
+    requirement: &MembershipRequirement,
+    token_guard: &ProgramAccount<TokenGuard>,
+    token_program: &AccountInfo<'info>,
+) -> ProgramResult {
+    let membership_token = membership_accounts
+        .get(0)
+        .ok_or(ErrorCode::NoMembershipToken)?;
+    // with membership alternatives, the accounts may be followed by unused accounts
+    let vault = membership_accounts
+        .get(membership_accounts_len(requirement) - 1)
+        .ok_or(ErrorCode::VaultAccountMismatch)?;
+    let token_account: spl_token::state::Account = assert_initialized(membership_token)?;
+
+    let (vault_authority, _) = derive_vault_authority(token_guard);
+    let expected_vault = get_associated_token_address(&vault_authority, &token_account.mint);
+    if expected_vault != *vault.key {
+        msg!("Expected vault {}", expected_vault);
+        return Err(ErrorCode::VaultAccountMismatch.into());
+    }
+
+    let amount = get_consumed_amount(&token_account, payer.key, requirement);
+
+    msg!(
+        "Collecting {} of membership token {}",
+        amount,
+        token_account.mint
+    );
+    spl_token_transfer(TokenTransferParams {
+        source: membership_token.clone(),
+        destination: vault.clone(),
+        amount,
+        authority: payer.to_account_info(),
+        authority_signer_seeds: &[],
+        token_program: token_program.clone(),
+    })
+}
+
+/// The amount of the presented membership token burned or collected on use:
+/// the configured amount, or if none, the minimum balance (at least one base unit) of an SPL token,
+/// so that a holder of several tickets does not lose them all at once, or the whole NFT
+pub fn get_consumed_amount(
+    token_account: &spl_token::state::Account,
+    payer: &Pubkey,
+    requirement: &MembershipRequirement,
+) -> u64 {
+    match (requirement.membership_consume_amount, &requirement.strategy) {
+        (0, Strategy::MembershipSPLToken) => requirement.min_membership_balance.max(1),
+        (0, _) => get_presented_amount(token_account, payer),
+        (amount, _) => amount,
+    }
+}
+
+/// The amount of the membership token that the payer presents:
+/// a delegate can only present (and spend) the amount delegated to it
+pub fn get_presented_amount(token_account: &spl_token::state::Account, payer: &Pubkey) -> u64 {
+    if token_account.owner == *payer {
+        token_account.amount
+    } else {
+        token_account.delegated_amount
+    }
+}
+
+/// The PDA that owns the token guard's vaults, i.e. the associated token accounts
+/// that collected membership tokens are transferred into
+pub fn derive_vault_authority(token_guard: &ProgramAccount<TokenGuard>) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            VAULT_AUTHORITY_SEED,
+            token_guard.to_account_info().key.as_ref(),
+        ],
+        &id(),
+    )
+}
+
+/// Check that the vault authority is the vault authority of the token guard, returning its bump
+pub fn check_vault_authority(
+    vault_authority: &AccountInfo,
+    token_guard: &ProgramAccount<TokenGuard>,
+) -> Result<u8, ProgramError> {
+    let (expected_vault_authority, bump) = derive_vault_authority(token_guard);
+    if expected_vault_authority != *vault_authority.key {
+        msg!("Expected vault authority {}", expected_vault_authority);
+        return Err(ErrorCode::VaultAuthorityMismatch.into());
+    }
+
+    Ok(bump)
+}
+
+/// Check that none of the vaults (passed by the client as every token account of the vault authority)
+/// hold collected membership tokens, which could not be withdrawn once the token guard is closed
+pub fn check_vaults_empty(
+    vaults: &[AccountInfo],
+    token_guard: &ProgramAccount<TokenGuard>,
+) -> ProgramResult {
+    let (vault_authority, _) = derive_vault_authority(token_guard);
+    for vault in vaults {
+        assert_owned_by(vault, &spl_token::id())?;
+        let token_account: spl_token::state::Account = assert_initialized(vault)?;
+        if token_account.owner != vault_authority {
+            msg!("{} is not a vault of the token guard", vault.key);
+            return Err(ErrorCode::VaultAccountMismatch.into());
+        }
+        if token_account.amount > 0 {
+            msg!(
+                "Vault {} holds {} collected membership tokens",
+                vault.key,
+                token_account.amount
+            );
+            return Err(ErrorCode::VaultNotEmpty.into());
         }
     }
 
     Ok(())
 }
 
+pub fn check_membership_token_owner(
+    token_account: &spl_token::state::Account,
+    payer: &Pubkey,
+    requirement: &MembershipRequirement,
+) -> ProgramResult {
+    if token_account.owner == *payer {
+        return Ok(());
+    }
+
+    // the token is held by another account (e.g. a custodian or staking program)
+    // which has delegated it to the payer
+    if requirement.allow_membership_delegate
+        && token_account.delegate == COption::Some(*payer)
+        && token_account.delegated_amount > 0
+    {
+        return Ok(());
+    }
+
+    msg!("Membership token account owner {}", token_account.owner);
+    Err(ErrorCode::MembershipTokenOwnerMismatch.into())
+}
+
 pub fn check_and_update_allowance<'info>(
     allowance_account_bump: u8,
     token_guard: &ProgramAccount<TokenGuard>,
+    phase: &Phase,
+    phase_start_time: Option<i64>,
     allowance_account: &mut AccountInfo<'info>,
     payer: &Signer<'info>,
     allowance_account_derive_key: &Pubkey,
@@ -176,16 +639,47 @@ pub fn check_and_update_allowance<'info>(
 ) -> ProgramResult {
     // Does the token guard have an allowance requirement?
     msg!("Checking allowance");
-    if token_guard.allowance > 0 {
+    if phase.allowance > 0 {
         // token guard has an allowance requirement
 
+        // should match deriveAllowanceAccount in the client
+        // allowances are counted separately in each phase, identified by its start time
+        // rather than its index, so that they survive the phases being replaced
+        let token_guard_key = token_guard.key().to_bytes();
+        let derive_key = allowance_account_derive_key.to_bytes();
+        let phase_seed = phase_start_time.map(|start_time| start_time.to_le_bytes());
+        let bump_seed = [allowance_account_bump];
+        let mut allowance_account_signer_seeds: Vec<&[u8]> = vec![
+            &ALLOWANCE_ACCOUNT_SEED[..],
+            &token_guard_key[..],
+            &derive_key[..],
+        ];
+        if let Some(phase_seed) = &phase_seed {
+            allowance_account_signer_seeds.push(phase_seed);
+        }
+
+        // whether or not it exists yet, the allowance account must be the one
+        // derived for this token guard and payer (or membership token)
+        check_allowance_account_address(
+            allowance_account,
+            &allowance_account_signer_seeds,
+            allowance_account_bump,
+        )?;
+        allowance_account_signer_seeds.push(&bump_seed);
+
         // if the allowance account does not exist, create it
         // if it exists, check if the value is already equal to the token guard allowance,
         // if so, error out, if not, increment it
         if allowance_account.owner == &id() {
             let mut allowance_program_account: ProgramAccount<AllowanceAccount> =
                 ProgramAccount::try_from(&id(), allowance_account)?;
-            if allowance_program_account.amount >= token_guard.allowance {
+            if allowance_program_account.token_guard != token_guard.key()
+                || allowance_program_account.subject != *allowance_account_derive_key
+            {
+                msg!("Allowance account belongs to a different token guard or subject");
+                return Err(ErrorCode::AllowanceAccountMismatch.into());
+            }
+            if allowance_program_account.amount >= phase.allowance {
                 msg!("Allowance of {} reached", allowance_program_account.amount);
                 return Err(ErrorCode::AllowanceExceeded.into());
             } else {
@@ -193,14 +687,7 @@ pub fn check_and_update_allowance<'info>(
                 allowance_program_account.exit(&id())?;
             }
         } else {
-            let size = (1 + 8) as usize;
-            // should match deriveAllowanceAccount in the client
-            let allowance_account_signer_seeds: &[&[_]] = &[
-                ALLOWANCE_ACCOUNT_SEED,
-                &token_guard.key().to_bytes(),
-                &allowance_account_derive_key.to_bytes(),
-                &[allowance_account_bump],
-            ];
+            let size = ALLOWANCE_ACCOUNT_SIZE;
 
             invoke_signed(
                 &system_instruction::create_account(
@@ -215,10 +702,14 @@ pub fn check_and_update_allowance<'info>(
                     allowance_account.to_account_info().clone(),
                     system_program.clone(),
                 ],
-                &[allowance_account_signer_seeds],
+                &[allowance_account_signer_seeds.as_slice()],
             )?;
 
-            let allowance: AllowanceAccount = AllowanceAccount { amount: 1 };
+            let allowance: AllowanceAccount = AllowanceAccount {
+                amount: 1,
+                token_guard: token_guard.key(),
+                subject: *allowance_account_derive_key,
+            };
             let info = allowance_account.to_account_info();
             let mut data = info.try_borrow_mut_data()?;
             let dst: &mut [u8] = &mut data;
@@ -231,6 +722,27 @@ pub fn check_and_update_allowance<'info>(
     Ok(())
 }
 
+/// The allowance account must be the PDA derived with the canonical bump,
+/// otherwise each other valid bump would give the payer a fresh allowance
+pub fn check_allowance_account_address(
+    allowance_account: &AccountInfo,
+    allowance_account_seeds: &[&[u8]],
+    allowance_account_bump: u8,
+) -> ProgramResult {
+    let (expected_address, expected_bump) =
+        Pubkey::find_program_address(allowance_account_seeds, &id());
+    if expected_address != *allowance_account.key || expected_bump != allowance_account_bump {
+        msg!(
+            "Expected allowance account {} with bump {}",
+            expected_address,
+            expected_bump
+        );
+        return Err(ErrorCode::AllowanceAccountMismatch.into());
+    }
+
+    Ok(())
+}
+
 pub fn transfer_lamports<'info>(
     lamports: u64,
     payer: &Signer<'info>,
@@ -256,18 +768,405 @@ pub fn transfer_lamports<'info>(
     Ok(())
 }
 
+/// Convert the amount paid into the amount of out tokens to mint,
+/// failing rather than rounding if the conversion is not exact
+pub fn apply_exchange_rate(
+    amount: u64,
+    token_guard: &ProgramAccount<TokenGuard>,
+) -> Result<u64, ProgramError> {
+    let scaled_amount = (amount as u128)
+        .checked_mul(token_guard.rate_numerator as u128)
+        .ok_or(ErrorCode::ExchangeRateOverflow)?;
+    let denominator = token_guard.rate_denominator as u128;
+    if scaled_amount % denominator != 0 {
+        msg!(
+            "{} does not convert exactly at rate {}/{}",
+            amount,
+            token_guard.rate_numerator,
+            token_guard.rate_denominator
+        );
+        return Err(ErrorCode::InexactExchangeRate.into());
+    }
+
+    let out_amount = scaled_amount / denominator;
+    if out_amount > u64::MAX as u128 {
+        return Err(ErrorCode::ExchangeRateOverflow.into());
+    }
+
+    Ok(out_amount as u64)
+}
+
+pub fn check_recipient(
+    recipient: &AccountInfo,
+    expected_recipient: &Pubkey,
+    token_guard: &ProgramAccount<TokenGuard>,
+) -> ProgramResult {
+    match token_guard.in_mint {
+        None => {
+            if recipient.key != expected_recipient {
+                msg!("Expected recipient {}", expected_recipient);
+                return Err(ErrorCode::RecipientMismatch.into());
+            }
+        }
+        Some(in_mint) => {
+            // the recipient is a token account owned by the expected recipient
+            assert_owned_by(recipient, &spl_token::id())?;
+            let token_account: spl_token::state::Account = assert_initialized(recipient)?;
+            if token_account.owner != *expected_recipient {
+                msg!(
+                    "Expected recipient token account owner {}",
+                    expected_recipient
+                );
+                return Err(ErrorCode::RecipientMismatch.into());
+            }
+            if token_account.mint != in_mint {
+                return Err(ErrorCode::MintMismatch.into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Split the amount between the recipients in proportion to their shares.
+/// The rounding dust goes to the first recipient.
+pub fn split_amount(amount: u64, recipients: &[RecipientShare]) -> Vec<u64> {
+    let mut amounts: Vec<u64> = recipients
+        .iter()
+        .map(|share| (amount as u128 * share.bps as u128 / TOTAL_BPS as u128) as u64)
+        .collect();
+
+    // the shares add up to TOTAL_BPS, so the amounts cannot add up to more than the amount
+    let dust = amount - amounts.iter().sum::<u64>();
+    amounts[0] += dust;
+
+    amounts
+}
+
+/// Pay the recipient, or split the payment between the recipients of the token guard,
+/// which are passed in order in the recipient accounts
+pub fn transfer_payment<'info>(
+    amount: u64,
+    token_guard: &ProgramAccount<TokenGuard>,
+    payer: &Signer<'info>,
+    payer_in_token_account: &AccountInfo<'info>,
+    recipient: &AccountInfo<'info>,
+    recipient_accounts: &[AccountInfo<'info>],
+    system_program: &AccountInfo<'info>,
+    token_program: &AccountInfo<'info>,
+) -> ProgramResult {
+    if token_guard.recipients.is_empty() {
+        return transfer_to_recipient(
+            amount,
+            token_guard,
+            payer,
+            payer_in_token_account,
+            recipient,
+            system_program,
+            token_program,
+        );
+    }
+
+    if recipient_accounts.len() < token_guard.recipients.len() {
+        return Err(ErrorCode::MissingRecipient.into());
+    }
+
+    let amounts = split_amount(amount, &token_guard.recipients);
+    for ((share, recipient_account), share_amount) in token_guard
+        .recipients
+        .iter()
+        .zip(recipient_accounts)
+        .zip(amounts)
+    {
+        check_recipient(recipient_account, &share.recipient, token_guard)?;
+        transfer_to_recipient(
+            share_amount,
+            token_guard,
+            payer,
+            payer_in_token_account,
+            recipient_account,
+            system_program,
+            token_program,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Pay the recipient in SOL, or in the in_mint token if the token guard has one
+pub fn transfer_to_recipient<'info>(
+    amount: u64,
+    token_guard: &ProgramAccount<TokenGuard>,
+    payer: &Signer<'info>,
+    payer_in_token_account: &AccountInfo<'info>,
+    recipient: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    token_program: &AccountInfo<'info>,
+) -> ProgramResult {
+    match token_guard.in_mint {
+        None => transfer_lamports(amount, payer, recipient, system_program),
+        Some(in_mint) => {
+            msg!(
+                "Sending {} tokens of mint {} from {} to {}",
+                amount,
+                in_mint,
+                payer_in_token_account.key,
+                recipient.key
+            );
+            spl_token_transfer(TokenTransferParams {
+                source: payer_in_token_account.clone(),
+                destination: recipient.clone(),
+                amount,
+                authority: payer.to_account_info(),
+                authority_signer_seeds: &[],
+                token_program: token_program.clone(),
+            })?;
+            msg!("Transfer complete");
+
+            Ok(())
+        }
+    }
+}
+
+pub fn check_schedule(start_time: Option<i64>, end_time: Option<i64>) -> ProgramResult {
+    if let (Some(start_time), Some(end_time)) = (start_time, end_time) {
+        // the end time is exclusive (see check_end_time),
+        // so a schedule ending at its start time would never be live
+        if end_time <= start_time {
+            msg!(
+                "End time {} is not after start time {}",
+                end_time,
+                start_time
+            );
+            return Err(ErrorCode::InvalidEndTime.into());
+        }
+    }
+
+    Ok(())
+}
+
+pub fn check_amount_limits(
+    min_amount: Option<u64>,
+    max_amount: Option<u64>,
+    fixed_price: Option<u64>,
+) -> ProgramResult {
+    if let (Some(min_amount), Some(max_amount)) = (min_amount, max_amount) {
+        if min_amount > max_amount {
+            msg!(
+                "Min amount {} exceeds max amount {}",
+                min_amount,
+                max_amount
+            );
+            return Err(ErrorCode::InvalidAmountLimits.into());
+        }
+    }
+
+    // every exchange pays the fixed price, so a price outside the limits would always fail
+    if let Some(fixed_price) = fixed_price {
+        let below_min = min_amount.map_or(false, |min_amount| fixed_price < min_amount);
+        let above_max = max_amount.map_or(false, |max_amount| fixed_price > max_amount);
+        if below_min || above_max {
+            msg!("Fixed price {} is outside the amount limits", fixed_price);
+            return Err(ErrorCode::InvalidFixedPrice.into());
+        }
+    }
+
+    Ok(())
+}
+
+pub fn check_phases(phases: &[Phase]) -> ProgramResult {
+    if phases.len() > MAX_PHASES {
+        msg!(
+            "{} phases exceeds the maximum of {}",
+            phases.len(),
+            MAX_PHASES
+        );
+        return Err(ErrorCode::TooManyPhases.into());
+    }
+
+    for phase in phases {
+        check_schedule(phase.start_time, phase.end_time)?;
+        check_amount_limits(phase.min_amount, phase.max_amount, phase.fixed_price)?;
+        check_membership(&phase.membership)?;
+    }
+
+    // the phases must be in order and must not overlap, so that only the first can start
+    // without a start time, and only the last can go on without an end time
+    for (phase, next_phase) in phases.iter().zip(phases.iter().skip(1)) {
+        match (phase.end_time, next_phase.start_time) {
+            (Some(end_time), Some(start_time)) if start_time >= end_time => {}
+            _ => {
+                msg!("Each phase must end before the next one starts");
+                return Err(ErrorCode::PhasesOverlap.into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Membership token strategies need a membership token to check against,
+/// and a token guard without a membership token must not have one.
+pub fn check_strategy(strategy: &Strategy, membership_token: &Option<Pubkey>) -> ProgramResult {
+    match (strategy, membership_token) {
+        (Strategy::GatewayOnly, None) => Ok(()),
+        (Strategy::GatewayOnly, Some(_)) => {
+            msg!("A membership token requires a membership token strategy");
+            Err(ErrorCode::InvalidStrategy.into())
+        }
+        (_, None) => {
+            msg!("Strategy {:?} requires a membership token", strategy);
+            Err(ErrorCode::InvalidStrategy.into())
+        }
+        (_, Some(_)) => Ok(()),
+    }
+}
+
+pub fn check_creator_index(creator_index: u8) -> ProgramResult {
+    if creator_index as usize >= MAX_CREATOR_LIMIT {
+        msg!(
+            "Creator index {} exceeds the maximum of {} creators",
+            creator_index,
+            MAX_CREATOR_LIMIT
+        );
+        return Err(ErrorCode::InvalidCreatorIndex.into());
+    }
+
+    Ok(())
+}
+
+pub fn check_membership_requirements(requirements: &[MembershipRequirement]) -> ProgramResult {
+    if requirements.len() > MAX_MEMBERSHIP_REQUIREMENTS {
+        msg!(
+            "{} membership requirements exceeds the maximum of {}",
+            requirements.len(),
+            MAX_MEMBERSHIP_REQUIREMENTS
+        );
+        return Err(ErrorCode::TooManyMembershipRequirements.into());
+    }
+
+    for requirement in requirements {
+        check_membership_requirement(requirement)?;
+    }
+
+    Ok(())
+}
+
+pub fn check_membership_alternatives(alternatives: &[MembershipRequirement]) -> ProgramResult {
+    if alternatives.len() > MAX_MEMBERSHIP_ALTERNATIVES {
+        msg!(
+            "{} membership alternatives exceeds the maximum of {}",
+            alternatives.len(),
+            MAX_MEMBERSHIP_ALTERNATIVES
+        );
+        return Err(ErrorCode::TooManyMembershipAlternatives.into());
+    }
+
+    for alternative in alternatives {
+        check_membership_requirement(alternative)?;
+    }
+
+    Ok(())
+}
+
+pub fn check_membership_requirement(requirement: &MembershipRequirement) -> ProgramResult {
+    // a requirement without a membership token requires nothing
+    if let Strategy::GatewayOnly = requirement.strategy {
+        return Err(ErrorCode::InvalidStrategy.into());
+    }
+
+    check_membership(requirement)
+}
+
+/// Check the membership token properties of the token guard, a phase or a membership requirement
+pub fn check_membership(membership: &MembershipRequirement) -> ProgramResult {
+    check_strategy(&membership.strategy, &membership.membership_token)?;
+    check_creator_index(membership.creator_index)?;
+    check_membership_token_use(
+        &membership.strategy,
+        membership.burn_membership_token,
+        membership.collect_membership_token,
+    )?;
+
+    Ok(())
+}
+
+/// A membership token can be burned or collected on use, but not both,
+/// and there must be a membership token to burn or collect
+pub fn check_membership_token_use(strategy: &Strategy, burn: bool, collect: bool) -> ProgramResult {
+    if burn && collect {
+        return Err(ErrorCode::ConflictingMembershipTokenUse.into());
+    }
+    if let Strategy::GatewayOnly = strategy {
+        if burn || collect {
+            msg!("Only a membership token can be burned or collected");
+            return Err(ErrorCode::InvalidStrategy.into());
+        }
+    }
+
+    Ok(())
+}
+
+pub fn check_recipient_shares(recipients: &[RecipientShare], recipient: &Pubkey) -> ProgramResult {
+    // no shares - the recipient receives the whole payment
+    if recipients.is_empty() {
+        return Ok(());
+    }
+
+    if recipients.len() > MAX_RECIPIENTS {
+        msg!(
+            "{} recipients exceeds the maximum of {}",
+            recipients.len(),
+            MAX_RECIPIENTS
+        );
+        return Err(ErrorCode::TooManyRecipients.into());
+    }
+
+    let total_bps: u32 = recipients.iter().map(|share| share.bps as u32).sum();
+    if total_bps != TOTAL_BPS as u32 {
+        msg!("Recipient shares add up to {} basis points", total_bps);
+        return Err(ErrorCode::InvalidRecipientShares.into());
+    }
+
+    // the first recipient receives the rounding dust
+    if recipients[0].recipient != *recipient {
+        msg!("The first recipient share must be for {}", recipient);
+        return Err(ErrorCode::RecipientMismatch.into());
+    }
+
+    Ok(())
+}
+
 pub fn set_properties(
     token_guard: &mut ProgramAccount<TokenGuard>,
-    mint_authority_bump: u8,
-    start_time: Option<i64>,
-    allowance: Option<u8>,
-    max_amount: Option<u64>,
-    strategy: Strategy,
-) {
-    token_guard.start_time = start_time;
+    recipient: &Pubkey,
+    properties: GuardProperties,
+) -> ProgramResult {
+    check_membership(&properties.membership)?;
+    check_schedule(properties.start_time, properties.end_time)?;
+    check_amount_limits(
+        properties.min_amount,
+        properties.max_amount,
+        properties.fixed_price,
+    )?;
+    if properties.rate_numerator == 0 || properties.rate_denominator == 0 {
+        return Err(ErrorCode::InvalidExchangeRate.into());
+    }
+    check_recipient_shares(&properties.recipients, recipient)?;
+
+    token_guard.gatekeeper_network = properties.gatekeeper_network;
+    token_guard.recipient = *recipient;
+    token_guard.start_time = properties.start_time;
+    token_guard.end_time = properties.end_time;
     // store zero as the "no allowance" rather than the extra byte an optional would require
-    token_guard.allowance = allowance.unwrap_or_default();
-    token_guard.max_amount = max_amount;
-    token_guard.mint_authority_bump = mint_authority_bump;
-    token_guard.strategy = strategy;
+    token_guard.allowance = properties.allowance.unwrap_or_default();
+    token_guard.max_amount = properties.max_amount;
+    token_guard.min_amount = properties.min_amount;
+    token_guard.fixed_price = properties.fixed_price;
+    token_guard.membership = properties.membership;
+    token_guard.rate_numerator = properties.rate_numerator;
+    token_guard.rate_denominator = properties.rate_denominator;
+    token_guard.recipients = properties.recipients;
+
+    Ok(())
 }
@@ -2,36 +2,64 @@ mod guard_utils;
 mod nft_utils;
 mod token_utils;
 
-extern crate num;
-#[macro_use]
-extern crate num_derive;
-
 use anchor_lang::{prelude::*, solana_program::system_program, AnchorDeserialize, AnchorSerialize};
 
 declare_id!("tg7bdEQom2SZT1JB2d77RDJFYaL4eZ2FcM8HZZAg5Z8");
 
 const MINT_AUTHORITY_SEED: &[u8; 30] = br"token_guard_out_mint_authority";
 const ALLOWANCE_ACCOUNT_SEED: &[u8; 29] = br"token_guard_allowance_account";
+const ALLOWANCE_ACCOUNT_SIZE: usize = 8 + 1 + 32 + 32;
+const VAULT_AUTHORITY_SEED: &[u8; 27] = br"token_guard_vault_authority";
 
-const TOKEN_GUARD_SIZE: usize = 8 + 32 + 32 + 32 + (1 + 32) + 32 + 1 + (1 + 8) + 1 + (1 + 8) + 1;
+const MEMBERSHIP_REQUIREMENT_SIZE: usize = 1 + (1 + 32) + 1 + 1 + 1 + 8 + 1 + 1 + 8;
+const MAX_PHASES: usize = 4;
+const PHASE_SIZE: usize =
+    (1 + 8) + (1 + 8) + MEMBERSHIP_REQUIREMENT_SIZE + 1 + (1 + 8) + (1 + 8) + (1 + 8);
+const MAX_MEMBERSHIP_REQUIREMENTS: usize = 3;
+const MAX_MEMBERSHIP_ALTERNATIVES: usize = 4;
+const MAX_RECIPIENTS: usize = 4;
+const RECIPIENT_SHARE_SIZE: usize = 32 + 2;
+// the shares of all recipients must add up to 100%, in basis points
+const TOTAL_BPS: u16 = 10_000;
+const TOKEN_GUARD_SIZE: usize = 8
+    + 32 // authority
+    + (1 + 32) // pending_authority
+    + 32 // recipient
+    + 32 // gatekeeper_network
+    + MEMBERSHIP_REQUIREMENT_SIZE // membership
+    + 32 // out_mint
+    + 1 // mint_authority_bump
+    + (1 + 32) // in_mint
+    + (1 + 8) // start_time
+    + (1 + 8) // end_time
+    + 1 // allowance
+    + (1 + 8) // max_amount
+    + (1 + 8) // min_amount
+    + (1 + 8) // fixed_price
+    + 1 // is_paused
+    + 8 // rate_numerator
+    + 8 // rate_denominator
+    + (4 + MAX_PHASES * PHASE_SIZE) // phases
+    + (4 + MAX_MEMBERSHIP_REQUIREMENTS * MEMBERSHIP_REQUIREMENT_SIZE) // membership_requirements
+    + (4 + MAX_MEMBERSHIP_ALTERNATIVES * MEMBERSHIP_REQUIREMENT_SIZE) // membership_alternatives
+    + (4 + MAX_RECIPIENTS * RECIPIENT_SHARE_SIZE); // recipients
 
 #[program]
 pub mod token_guard {
     use super::*;
     use crate::{
         guard_utils::*,
-        token_utils::{spl_token_mint, TokenMintParams},
+        token_utils::{
+            assert_initialized, spl_token_mint, spl_token_set_mint_authority, spl_token_transfer,
+            TokenMintParams, TokenSetAuthorityParams, TokenTransferParams,
+        },
     };
 
     pub fn initialize(
         ctx: Context<Initialize>,
-        gatekeeper_network: Pubkey,
         mint_authority_bump: u8,
-        start_time: Option<i64>,
-        allowance: Option<u8>,
-        max_amount: Option<u64>,
-        membership_token: Option<Pubkey>,
-        strategy: u8, // Type: Strategy- Anchor does not yet provide mappings for enums
+        in_mint: Option<Pubkey>,
+        properties: GuardProperties,
     ) -> ProgramResult {
         let token_guard = &mut ctx.accounts.token_guard;
         let out_mint = &ctx.accounts.out_mint;
@@ -41,27 +69,157 @@ pub mod token_guard {
         check_out_mint(out_mint, mint_authority)?;
 
         token_guard.authority = *ctx.accounts.authority.key;
-        token_guard.gatekeeper_network = gatekeeper_network;
-        token_guard.recipient = *ctx.accounts.recipient.key;
         // token_guard.recipient_ata = *ctx.accounts.recipient_ata.key;
         token_guard.out_mint = *ctx.accounts.out_mint.key;
-        token_guard.membership_token = membership_token;
+        token_guard.mint_authority_bump = mint_authority_bump;
+        // if set, payment is made in this SPL token rather than SOL
+        token_guard.in_mint = in_mint;
 
-        set_properties(
-            token_guard,
-            mint_authority_bump,
-            start_time,
-            allowance,
-            max_amount,
-            num::FromPrimitive::from_u8(strategy).unwrap(),
-        );
+        set_properties(token_guard, ctx.accounts.recipient.key, properties)?;
+
+        Ok(())
+    }
+
+    pub fn update_guard(ctx: Context<UpdateGuard>, properties: GuardProperties) -> ProgramResult {
+        let token_guard = &mut ctx.accounts.token_guard;
+
+        // the out mint and its mint authority are fixed at initialize
+        set_properties(token_guard, ctx.accounts.recipient.key, properties)?;
+
+        Ok(())
+    }
+
+    pub fn propose_authority(
+        ctx: Context<ProposeAuthority>,
+        pending_authority: Option<Pubkey>,
+    ) -> ProgramResult {
+        // the authority only changes once the pending authority accepts,
+        // so a mistyped key can be replaced, or the proposal withdrawn with None
+        ctx.accounts.token_guard.pending_authority = pending_authority;
+
+        Ok(())
+    }
+
+    pub fn accept_authority(ctx: Context<AcceptAuthority>) -> ProgramResult {
+        let token_guard = &mut ctx.accounts.token_guard;
+        let pending_authority = &ctx.accounts.pending_authority;
+
+        check_pending_authority(pending_authority, token_guard)?;
+
+        token_guard.authority = *pending_authority.key;
+        token_guard.pending_authority = None;
+
+        Ok(())
+    }
+
+    pub fn close_guard(
+        ctx: Context<CloseGuard>,
+        new_mint_authority: Option<Pubkey>,
+    ) -> ProgramResult {
+        let token_guard = &ctx.accounts.token_guard;
+
+        // the vaults are owned by a PDA of the token guard,
+        // so their tokens cannot be withdrawn once it is closed
+        check_vaults_empty(ctx.remaining_accounts, token_guard)?;
+
+        // hand the out mint back (or disable minting altogether if None)
+        // before the token guard, and with it the mint authority bump, is gone
+        spl_token_set_mint_authority(TokenSetAuthorityParams {
+            mint: ctx.accounts.out_mint.clone(),
+            new_authority: new_mint_authority.as_ref(),
+            mint_authority: ctx.accounts.mint_authority.clone(),
+            authority_signer_seeds: &[
+                MINT_AUTHORITY_SEED,
+                &token_guard.to_account_info().key.to_bytes(),
+                &[token_guard.mint_authority_bump],
+            ],
+            token_program: ctx.accounts.token_program.clone(),
+        })?;
+
+        // the token guard account itself is closed by the `close` constraint
+        Ok(())
+    }
+
+    pub fn pause(ctx: Context<SetPaused>) -> ProgramResult {
+        ctx.accounts.token_guard.is_paused = true;
+
+        Ok(())
+    }
+
+    pub fn resume(ctx: Context<SetPaused>) -> ProgramResult {
+        ctx.accounts.token_guard.is_paused = false;
+
+        Ok(())
+    }
+
+    pub fn set_phases(ctx: Context<SetPhases>, phases: Vec<Phase>) -> ProgramResult {
+        check_phases(&phases)?;
+
+        // an empty list reverts to the top-level sale properties
+        ctx.accounts.token_guard.phases = phases;
+
+        Ok(())
+    }
+
+    pub fn set_membership_requirements(
+        ctx: Context<SetMembershipRequirements>,
+        membership_requirements: Vec<MembershipRequirement>,
+    ) -> ProgramResult {
+        check_membership_requirements(&membership_requirements)?;
+
+        // an empty list removes the additional requirements
+        ctx.accounts.token_guard.membership_requirements = membership_requirements;
+
+        Ok(())
+    }
+
+    pub fn set_membership_alternatives(
+        ctx: Context<SetMembershipAlternatives>,
+        membership_alternatives: Vec<MembershipRequirement>,
+    ) -> ProgramResult {
+        check_membership_alternatives(&membership_alternatives)?;
+
+        // an empty list accepts only the membership token of the token guard or its phases
+        ctx.accounts.token_guard.membership_alternatives = membership_alternatives;
+
+        Ok(())
+    }
+
+    pub fn withdraw_collected(
+        ctx: Context<WithdrawCollected>,
+        amount: Option<u64>,
+    ) -> ProgramResult {
+        let token_guard = &ctx.accounts.token_guard;
+        let vault_authority = &ctx.accounts.vault_authority;
+        let vault = &ctx.accounts.vault;
+
+        let vault_authority_bump = check_vault_authority(vault_authority, token_guard)?;
+
+        // withdraw everything collected in the vault, unless an amount is given
+        let amount = match amount {
+            Some(amount) => amount,
+            None => assert_initialized::<spl_token::state::Account>(vault)?.amount,
+        };
+
+        spl_token_transfer(TokenTransferParams {
+            source: vault.clone(),
+            destination: ctx.accounts.destination.clone(),
+            amount,
+            authority: vault_authority.clone(),
+            authority_signer_seeds: &[
+                VAULT_AUTHORITY_SEED,
+                &token_guard.to_account_info().key.to_bytes(),
+                &[vault_authority_bump],
+            ],
+            token_program: ctx.accounts.token_program.clone(),
+        })?;
 
         Ok(())
     }
 
     pub fn exchange(
         ctx: Context<Exchange>,
-        lamports: u64,
+        amount: Option<u64>, // may be omitted if the token guard has a fixed price
         allowance_account_bump: u8,
     ) -> ProgramResult {
         msg!("exchange");
@@ -69,6 +227,7 @@ pub mod token_guard {
         let mut allowance_account = &mut ctx.accounts.allowance_account;
         let payer = &ctx.accounts.payer;
         let payer_ata = &ctx.accounts.payer_ata;
+        let payer_in_token_account = &ctx.accounts.payer_in_token_account;
         let clock = &ctx.accounts.clock;
         let gateway_token = &ctx.accounts.gateway_token;
         let recipient = &ctx.accounts.recipient;
@@ -78,25 +237,66 @@ pub mod token_guard {
         let system_program = &ctx.accounts.system_program;
         let token_program = &ctx.accounts.token_program;
 
-        let membership_token = &ctx.remaining_accounts.get(0);
-        let membership_token_mint = &ctx.remaining_accounts.get(1);
-        let membership_token_metadata = &ctx.remaining_accounts.get(2);
+        // the sale properties of the live phase,
+        // or of the token guard itself if it has no phases
+        let (phase_index, phase) = get_active_phase(clock, token_guard);
+
+        // the membership token of the phase (or one of its alternatives),
+        // followed by those required in every phase
+        let membership_requirements = get_membership_requirements(&phase, token_guard);
+
+        // the remaining accounts are the membership token accounts required by the strategy
+        // of each membership requirement, followed by the recipients of a split payment, in order
+        let (membership_account_groups, recipient_accounts) =
+            split_remaining_accounts(ctx.remaining_accounts, &membership_requirements);
+
+        check_not_paused(token_guard)?;
+        check_start_time(clock, &phase)?;
+        check_end_time(clock, &phase)?;
+        let amount = get_amount(amount, &phase)?;
+        check_min_amount(amount, &phase)?;
+        check_max_amount(amount, &phase)?;
+        check_gateway_token(gateway_token, payer, token_guard)?;
+        check_recipient(recipient, &token_guard.recipient, token_guard)?;
+        check_balance(amount, payer, payer_in_token_account, token_guard)?;
+        check_payer_token_account(payer_ata, payer, token_guard)?;
+        check_mint_authority_account(mint_authority, token_guard)?;
+        let out_amount = apply_exchange_rate(amount, token_guard)?;
+
+        // fail on the first membership requirement that is not met by any of its options
+        let mut presented_requirements = Vec::with_capacity(membership_requirements.len());
+        for (options, membership_accounts) in membership_requirements
+            .iter()
+            .zip(membership_account_groups.iter())
+        {
+            presented_requirements.push(check_any_membership_token(
+                membership_accounts,
+                payer.key,
+                options,
+            )?);
+        }
 
         // If there is a membership token NFT, and an allowance
         // then the allowance is based on that NFT, rather than the user's wallet
         // in other words, the user cannot use the NFT more than x times,
         // Even if the NFT is transferred to another user, it cannot be used again.
         // If there is no membership token NFT, then the allowance is based on the user's wallet.
+        // With membership alternatives, this depends on the option the presented token meets.
+        let membership_token_mint = match presented_requirements.first().map(|r| &r.strategy) {
+            None | Some(Strategy::GatewayOnly) | Some(Strategy::MembershipSPLToken) => None,
+            Some(_) => membership_account_groups
+                .first()
+                .and_then(|membership_accounts| membership_accounts.get(1)),
+        };
         let allowance_account_derive_key = membership_token_mint.map_or(payer.key, |m| m.key);
+        // only the first phase may have no start time
+        let phase_start_time = phase_index.map(|_| phase.start_time.unwrap_or_default());
 
-        check_start_time(clock, token_guard)?;
-        check_max_amount(lamports, token_guard)?;
-        check_gateway_token(gateway_token, payer, token_guard)?;
-        check_balance(lamports, payer)?;
-        check_payer_token_account(payer_ata, token_guard)?;
         check_and_update_allowance(
             allowance_account_bump,
             &token_guard,
+            &phase,
+            phase_start_time,
             &mut allowance_account,
             &payer,
             &allowance_account_derive_key,
@@ -104,15 +304,36 @@ pub mod token_guard {
             &system_program,
         )?;
 
-        check_membership_token(
-            membership_token,
-            membership_token_mint,
-            membership_token_metadata,
+        // consume the membership tokens of the requirements that burn or collect them
+        for (requirement, membership_accounts) in presented_requirements
+            .iter()
+            .zip(membership_account_groups.iter())
+        {
+            if requirement.burn_membership_token {
+                burn_membership_token(membership_accounts, payer, requirement, token_program)?;
+            }
+            if requirement.collect_membership_token {
+                collect_membership_token(
+                    membership_accounts,
+                    payer,
+                    requirement,
+                    token_guard,
+                    token_program,
+                )?;
+            }
+        }
+
+        transfer_payment(
+            amount,
             token_guard,
+            &payer,
+            &payer_in_token_account,
+            &recipient,
+            recipient_accounts,
+            &system_program,
+            &token_program,
         )?;
 
-        transfer_lamports(lamports, &payer, &recipient, &system_program)?;
-
         // mint out tokens to the payer
         spl_token_mint(TokenMintParams {
             mint: out_mint.clone(),
@@ -124,7 +345,7 @@ pub mod token_guard {
                 &[token_guard.mint_authority_bump],
             ],
             token_program: token_program.clone(),
-            amount: lamports,
+            amount: out_amount,
         })?;
 
         Ok(())
@@ -153,12 +374,96 @@ pub struct Initialize<'info> {
 }
 
 #[derive(Accounts)]
-#[instruction(amount: u64, allowance_account_bump: u8)]
+pub struct UpdateGuard<'info> {
+    #[account(mut, has_one = authority)]
+    token_guard: ProgramAccount<'info, TokenGuard>,
+    authority: Signer<'info>,
+    #[account()]
+    recipient: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ProposeAuthority<'info> {
+    #[account(mut, has_one = authority)]
+    token_guard: ProgramAccount<'info, TokenGuard>,
+    authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAuthority<'info> {
+    #[account(mut)]
+    token_guard: ProgramAccount<'info, TokenGuard>,
+    pending_authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseGuard<'info> {
+    #[account(mut, has_one = authority, has_one = out_mint, close = destination)]
+    token_guard: ProgramAccount<'info, TokenGuard>,
+    authority: Signer<'info>,
+    // receives the rent of the token guard account
+    #[account(mut)]
+    destination: AccountInfo<'info>,
+    #[account(mut)]
+    out_mint: AccountInfo<'info>,
+    #[account()]
+    mint_authority: AccountInfo<'info>,
+    #[account(address = spl_token::id())]
+    token_program: AccountInfo<'info>,
+    // the remaining accounts are the vaults of the token guard (see check_vaults_empty)
+}
+
+#[derive(Accounts)]
+pub struct SetPaused<'info> {
+    #[account(mut, has_one = authority)]
+    token_guard: ProgramAccount<'info, TokenGuard>,
+    authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetPhases<'info> {
+    #[account(mut, has_one = authority)]
+    token_guard: ProgramAccount<'info, TokenGuard>,
+    authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetMembershipRequirements<'info> {
+    #[account(mut, has_one = authority)]
+    token_guard: ProgramAccount<'info, TokenGuard>,
+    authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetMembershipAlternatives<'info> {
+    #[account(mut, has_one = authority)]
+    token_guard: ProgramAccount<'info, TokenGuard>,
+    authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawCollected<'info> {
+    #[account(has_one = authority)]
+    token_guard: ProgramAccount<'info, TokenGuard>,
+    authority: Signer<'info>,
+    // the PDA that owns the vaults of the token guard (see check_vault_authority)
+    #[account()]
+    vault_authority: AccountInfo<'info>,
+    // the vault to withdraw from, i.e. the vault authority's token account for a membership token
+    #[account(mut)]
+    vault: AccountInfo<'info>,
+    #[account(mut)]
+    destination: AccountInfo<'info>,
+    #[account(address = spl_token::id())]
+    token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(amount: Option<u64>, allowance_account_bump: u8)]
 pub struct Exchange<'info> {
     #[account(
   // has_one = out_mint,
   // has_one = recipient_ata,
-  // has_one = recipient,
   // has_one = mint_authority
   )]
     token_guard: ProgramAccount<'info, TokenGuard>,
@@ -170,6 +475,14 @@ pub struct Exchange<'info> {
     #[account(mut, address = token_guard.out_mint)]
     // out_mint: ProgramAccount<'info, anchor_spl::token::Mint>,
     out_mint: AccountInfo<'info>,
+    // the payer's token account for the in_mint, if the token guard accepts SPL tokens
+    // (unused if the token guard accepts SOL)
+    #[account(mut)]
+    payer_in_token_account: AccountInfo<'info>,
+    // the recipient's wallet, or their token account for the in_mint,
+    // if the token guard accepts SPL tokens.
+    // Not a has_one constraint, as the token account address differs from the recipient
+    // (see check_recipient)
     #[account(mut)]
     recipient: AccountInfo<'info>,
     #[account()]
@@ -202,11 +515,13 @@ pub struct Exchange<'info> {
     rent: Sysvar<'info, Rent>,
 }
 
-#[derive(Clone, Debug, AnchorDeserialize, AnchorSerialize, FromPrimitive)]
+#[derive(Clone, Debug, AnchorDeserialize, AnchorSerialize)]
 pub enum Strategy {
     GatewayOnly = 0,
     MembershipSPLToken = 1,
     MembershipNftCreator = 2,
+    MembershipNftCollection = 3,
+    MembershipNftAnyCreator = 4,
 }
 impl Default for Strategy {
     fn default() -> Self {
@@ -214,28 +529,118 @@ impl Default for Strategy {
     }
 }
 
+/// The sale properties of one phase of a multi-phase sale,
+/// e.g. a membership token presale followed by a public sale.
+#[derive(Clone, Debug, Default, AnchorDeserialize, AnchorSerialize)]
+pub struct Phase {
+    pub start_time: Option<i64>,
+    pub end_time: Option<i64>,
+    pub membership: MembershipRequirement,
+    pub allowance: u8,
+    pub max_amount: Option<u64>,
+    pub min_amount: Option<u64>,
+    pub fixed_price: Option<u64>,
+}
+
+/// A membership token that must be presented in an exchange, and how it is checked.
+/// With the GatewayOnly strategy, no membership token is required.
+#[derive(Clone, Debug, Default, AnchorDeserialize, AnchorSerialize)]
+pub struct MembershipRequirement {
+    pub strategy: Strategy,
+    pub membership_token: Option<Pubkey>,
+    // if set, the membership token may be held in an account delegated to the payer,
+    // rather than owned by them, e.g. for custodial or staking setups
+    pub allow_membership_delegate: bool,
+    // the position in the metadata creators array of the creator to match
+    // with the MembershipNftCreator strategy
+    pub creator_index: u8,
+    // if set, NFT membership tokens must have a master edition account,
+    // which is passed after the metadata account
+    pub require_master_edition: bool,
+    // the balance of the membership token required with the MembershipSPLToken strategy,
+    // zero requires any positive balance
+    pub min_membership_balance: u64,
+    // if set, the presented membership token is burned by the exchange,
+    // so it can only be redeemed once, regardless of the allowance
+    pub burn_membership_token: bool,
+    // if set, the presented membership token is transferred into the token guard's vault
+    // for its mint, from which the authority can withdraw it, e.g. to reissue it
+    pub collect_membership_token: bool,
+    // the amount of the membership token burned or collected,
+    // zero consumes the whole balance presented
+    pub membership_consume_amount: u64,
+}
+
+/// The properties of a token guard that the authority sets with initialize and update_guard
+#[derive(Clone, Debug, Default, AnchorDeserialize, AnchorSerialize)]
+pub struct GuardProperties {
+    pub gatekeeper_network: Pubkey,
+    pub start_time: Option<i64>,
+    pub end_time: Option<i64>,
+    pub allowance: Option<u8>,
+    pub max_amount: Option<u64>,
+    pub min_amount: Option<u64>,
+    pub fixed_price: Option<u64>,
+    pub membership: MembershipRequirement,
+    pub rate_numerator: u64,
+    pub rate_denominator: u64,
+    pub recipients: Vec<RecipientShare>,
+}
+
+/// A recipient's share of each payment, when payments are split between several recipients
+#[derive(Clone, Debug, Default, AnchorDeserialize, AnchorSerialize)]
+pub struct RecipientShare {
+    pub recipient: Pubkey,
+    pub bps: u16,
+}
+
+// NOTE: this layout (and its size) is not compatible with TokenGuard accounts
+// created by earlier versions of the program, which cannot be deserialized or resized
+// by an upgrade, so this version must be deployed as a new program (see the README)
 #[account]
 #[derive(Default)]
 pub struct TokenGuard {
     pub authority: Pubkey,
+    pub pending_authority: Option<Pubkey>,
     pub recipient: Pubkey,
     // pub recipient_ata: Pubkey,
     pub gatekeeper_network: Pubkey,
-    pub membership_token: Option<Pubkey>,
+    pub membership: MembershipRequirement,
     pub out_mint: Pubkey,
     pub mint_authority_bump: u8,
-    // pub in_mint: Option<Pubkey>,
+    pub in_mint: Option<Pubkey>,
     pub start_time: Option<i64>, // i64 because that is the type of clock.unix_timestamp
+    pub end_time: Option<i64>,
     // pub gt_expiry_tolerance: u32,
     pub allowance: u8,
     pub max_amount: Option<u64>,
-    pub strategy: Strategy,
+    pub min_amount: Option<u64>,
+    // if set, every exchange must pay exactly this amount
+    pub fixed_price: Option<u64>,
+    pub is_paused: bool,
+    // the number of out tokens minted per input token (or lamport) is
+    // rate_numerator / rate_denominator
+    pub rate_numerator: u64,
+    pub rate_denominator: u64,
+    // if set, the phases replace the top-level sale properties
+    pub phases: Vec<Phase>,
+    // membership tokens that must be presented in addition to that of the live phase (if any)
+    pub membership_requirements: Vec<MembershipRequirement>,
+    // membership tokens accepted in place of that of the live phase (if any),
+    // e.g. NFTs from any of several partner collections
+    pub membership_alternatives: Vec<MembershipRequirement>,
+    // if set, payments are split between these recipients, the first of which is the recipient
+    pub recipients: Vec<RecipientShare>,
 }
 
+// NOTE: as with TokenGuard, this layout differs from that of earlier versions of the program
 #[account]
 #[derive(Default)]
 pub struct AllowanceAccount {
     pub amount: u8,
+    // the token guard and the payer (or membership token mint) the allowance is counted for
+    pub token_guard: Pubkey,
+    pub subject: Pubkey,
 }
 
 #[error]
@@ -270,4 +675,86 @@ pub enum ErrorCode {
     InvalidStrategy,
     #[msg("The presented membership token has an unverified creator")]
     UnverifiedMembershipTokenCreator,
+    #[msg("The signer is not the pending authority of this TokenGuard")]
+    PendingAuthorityMismatch,
+    #[msg("Setting the mint authority failed")]
+    SetAuthorityFailed,
+    #[msg("TokenGuard is paused")]
+    Paused,
+    #[msg("TokenGuard has ended")]
+    Ended,
+    #[msg("The end time must be after the start time")]
+    InvalidEndTime,
+    #[msg("A TokenGuard cannot have more than 4 phases")]
+    TooManyPhases,
+    #[msg("The exchange rate numerator and denominator must be greater than zero")]
+    InvalidExchangeRate,
+    #[msg("The amount cannot be converted exactly at the exchange rate of this TokenGuard")]
+    InexactExchangeRate,
+    #[msg("The amount overflows when converted at the exchange rate of this TokenGuard")]
+    ExchangeRateOverflow,
+    #[msg("The amount must equal the fixed price of this TokenGuard")]
+    IncorrectPrice,
+    #[msg("The amount is below the minimum amount allowed by this TokenGuard")]
+    MinAmountNotReached,
+    #[msg("An amount is required, as this TokenGuard does not have a fixed price")]
+    MissingAmount,
+    #[msg("The minimum amount must not exceed the maximum amount")]
+    InvalidAmountLimits,
+    #[msg("The fixed price must be within the minimum and maximum amounts")]
+    InvalidFixedPrice,
+    #[msg("A TokenGuard cannot have more than 4 recipients")]
+    TooManyRecipients,
+    #[msg("The recipient shares must add up to 10000 basis points")]
+    InvalidRecipientShares,
+    #[msg("The recipient does not match the recipient of this TokenGuard")]
+    RecipientMismatch,
+    #[msg("A recipient of the split payment is missing")]
+    MissingRecipient,
+    #[msg("The allowance account was not derived for this TokenGuard and payer")]
+    AllowanceAccountMismatch,
+    #[msg("The membership token account is not owned by (or delegated to) the payer")]
+    MembershipTokenOwnerMismatch,
+    #[msg("The metadata account is not owned by the token metadata program")]
+    MetadataAccountOwnerMismatch,
+    #[msg("The metadata account is not the metadata account of the membership token mint")]
+    MetadataAccountMismatch,
+    #[msg("The payer's token account is not owned by the payer")]
+    TokenAccountOwnerMismatch,
+    #[msg("The metadata of the presented membership token has no creators")]
+    NoCreators,
+    #[msg("The metadata account could not be decoded")]
+    InvalidMetadata,
+    #[msg("The metadata of the presented membership token has no collection")]
+    NoCollection,
+    #[msg("The presented membership token has an unverified collection")]
+    UnverifiedMembershipTokenCollection,
+    #[msg("The creator index exceeds the maximum number of creators of an NFT")]
+    InvalidCreatorIndex,
+    #[msg("An NFT membership token must have zero decimals")]
+    InvalidNftDecimals,
+    #[msg("An NFT membership token must have a supply of one")]
+    InvalidNftSupply,
+    #[msg("The presented NFT membership token has no master edition")]
+    NoMasterEdition,
+    #[msg("The master edition account is not the master edition of the membership token mint")]
+    MasterEditionAccountMismatch,
+    #[msg("A TokenGuard cannot have more than 3 membership requirements")]
+    TooManyMembershipRequirements,
+    #[msg("A TokenGuard cannot have more than 4 membership alternatives")]
+    TooManyMembershipAlternatives,
+    #[msg("Burning the membership token failed")]
+    TokenBurnFailed,
+    #[msg("A membership token cannot be both burned and collected")]
+    ConflictingMembershipTokenUse,
+    #[msg("The vault is not the token guard's vault for the membership token mint")]
+    VaultAccountMismatch,
+    #[msg("The vault authority is not the vault authority of this TokenGuard")]
+    VaultAuthorityMismatch,
+    #[msg("The TokenGuard cannot be closed while its vaults hold collected membership tokens")]
+    VaultNotEmpty,
+    #[msg("Each phase must end before the next one starts")]
+    PhasesOverlap,
+    #[msg("The metadata of the presented membership token has no creator at the creator index")]
+    CreatorIndexOutOfRange,
 }
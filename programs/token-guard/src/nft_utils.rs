@@ -1,39 +1,223 @@
-use crate::{ErrorCode, Strategy, TokenGuard};
-use anchor_lang::{
-    prelude::{msg, AccountInfo, ProgramResult},
-    ProgramAccount,
+use crate::{ErrorCode, MembershipRequirement, Strategy};
+use anchor_lang::prelude::{
+    msg, AccountInfo, AnchorDeserialize, ProgramError, ProgramResult, Pubkey,
 };
-use metaplex_token_metadata::state::Metadata;
+use metaplex_token_metadata::state::{Key, EDITION, PREFIX};
+
+// The metaplex-token-metadata crate only decodes the original metadata layout,
+// so we decode the fields we need from the newer layout (which adds the collection) ourselves.
+// Fields added after `collection` are not needed, so are not decoded.
+// Accounts created with the original layout are zero-padded, so the newer fields decode as None.
+
+#[derive(AnchorDeserialize)]
+pub struct MetadataCreator {
+    pub address: Pubkey,
+    pub verified: bool,
+    pub share: u8,
+}
+
+#[derive(AnchorDeserialize)]
+pub struct MetadataCollection {
+    pub verified: bool,
+    pub key: Pubkey,
+}
+
+#[derive(AnchorDeserialize)]
+pub struct MetadataData {
+    pub name: String,
+    pub symbol: String,
+    pub uri: String,
+    pub seller_fee_basis_points: u16,
+    pub creators: Option<Vec<MetadataCreator>>,
+}
+
+#[derive(AnchorDeserialize)]
+pub struct NftMetadata {
+    pub key: u8,
+    pub update_authority: Pubkey,
+    pub mint: Pubkey,
+    pub data: MetadataData,
+    pub primary_sale_happened: bool,
+    pub is_mutable: bool,
+    pub edition_nonce: Option<u8>,
+    pub token_standard: Option<u8>,
+    pub collection: Option<MetadataCollection>,
+}
+
+pub fn decode_metadata(metadata_account: &AccountInfo) -> Result<NftMetadata, ProgramError> {
+    let data = metadata_account.try_borrow_data()?;
+    // deserialize rather than try_from_slice, as the account is longer than the decoded fields
+    let metadata =
+        NftMetadata::deserialize(&mut &data[..]).map_err(|_| ErrorCode::InvalidMetadata)?;
+    if metadata.key != Key::MetadataV1 as u8 {
+        return Err(ErrorCode::InvalidMetadata.into());
+    }
+
+    Ok(metadata)
+}
+
+/// Check that the metadata account is the one created by the token metadata program for the mint.
+/// Otherwise, anyone could present a forged metadata account with a verified creator.
+pub fn check_metadata_account(
+    metadata_account: &AccountInfo,
+    membership_token_mint: &AccountInfo,
+) -> ProgramResult {
+    if *metadata_account.owner != metaplex_token_metadata::id() {
+        msg!("Metadata account owner {}", metadata_account.owner);
+        return Err(ErrorCode::MetadataAccountOwnerMismatch.into());
+    }
+
+    let metadata_program_id = metaplex_token_metadata::id();
+    let (expected_metadata_account, _) = Pubkey::find_program_address(
+        &[
+            PREFIX.as_bytes(),
+            metadata_program_id.as_ref(),
+            membership_token_mint.key.as_ref(),
+        ],
+        &metadata_program_id,
+    );
+    if expected_metadata_account != *metadata_account.key {
+        msg!("Expected metadata account {}", expected_metadata_account);
+        return Err(ErrorCode::MetadataAccountMismatch.into());
+    }
+
+    Ok(())
+}
+
+/// Check that the master edition account is the master edition created by the token metadata program
+/// for the mint. Prints of a master edition are not accepted.
+pub fn check_master_edition(
+    master_edition_account: &AccountInfo,
+    membership_token_mint: &AccountInfo,
+) -> ProgramResult {
+    let metadata_program_id = metaplex_token_metadata::id();
+    let (expected_master_edition_account, _) = Pubkey::find_program_address(
+        &[
+            PREFIX.as_bytes(),
+            metadata_program_id.as_ref(),
+            membership_token_mint.key.as_ref(),
+            EDITION.as_bytes(),
+        ],
+        &metadata_program_id,
+    );
+    if expected_master_edition_account != *master_edition_account.key {
+        msg!(
+            "Expected master edition account {}",
+            expected_master_edition_account
+        );
+        return Err(ErrorCode::MasterEditionAccountMismatch.into());
+    }
+
+    // the token metadata program has not created an edition for the mint
+    if *master_edition_account.owner != metadata_program_id {
+        msg!("Membership token has no edition");
+        return Err(ErrorCode::NoMasterEdition.into());
+    }
+
+    // the edition account of a print holds an edition rather than a master edition
+    let data = master_edition_account.try_borrow_data()?;
+    let key = data.first().copied().unwrap_or(Key::Uninitialized as u8);
+    if key != Key::MasterEditionV1 as u8 && key != Key::MasterEditionV2 as u8 {
+        msg!("Membership token edition is not a master edition");
+        return Err(ErrorCode::NoMasterEdition.into());
+    }
+
+    Ok(())
+}
 
 pub fn check_nft_metadata(
     metadata_account: &AccountInfo,
     membership_token_mint: &AccountInfo,
-    token_guard: &ProgramAccount<TokenGuard>,
+    requirement: &MembershipRequirement,
 ) -> ProgramResult {
-    let metadata = Metadata::from_account_info(metadata_account)?;
+    check_metadata_account(metadata_account, membership_token_mint)?;
+
+    let metadata = decode_metadata(metadata_account)?;
 
     if metadata.mint != *membership_token_mint.key {
         return Err(ErrorCode::MembershipTokenMismatch.into());
     }
 
-    match token_guard.strategy {
-        Strategy::MembershipNftCreator => match token_guard.membership_token {
+    match requirement.strategy {
+        Strategy::MembershipNftCreator => match requirement.membership_token {
             None => return Err(ErrorCode::InvalidStrategy.into()),
             Some(key_to_match) => {
-                let first_creator = &metadata.data.creators.unwrap()[0];
-                if first_creator.address != key_to_match {
-                    msg!("Metadata creator does not match membership token");
+                let creators = metadata
+                    .data
+                    .creators
+                    .as_ref()
+                    .ok_or(ErrorCode::NoCreators)?;
+                // the first creator, unless the token guard pins another position
+                let creator = creators
+                    .get(requirement.creator_index as usize)
+                    .ok_or_else(|| {
+                        msg!(
+                            "Metadata has {} creators, so none at index {}",
+                            creators.len(),
+                            requirement.creator_index
+                        );
+                        ErrorCode::CreatorIndexOutOfRange
+                    })?;
+                if creator.address != key_to_match {
+                    msg!(
+                        "Metadata creator {} does not match membership token",
+                        requirement.creator_index
+                    );
                     return Err(ErrorCode::MembershipTokenMismatch.into());
                 }
 
                 // in order to prevent users from minting their own NFTs and claiming
                 // it belongs to the collection, we only allow verified creators here.
-                if !first_creator.verified {
+                if !creator.verified {
+                    msg!("NFT creator is not verified");
+                    return Err(ErrorCode::UnverifiedMembershipTokenCreator.into());
+                }
+            }
+        },
+        Strategy::MembershipNftAnyCreator => match requirement.membership_token {
+            None => return Err(ErrorCode::InvalidStrategy.into()),
+            Some(key_to_match) => {
+                let creators = metadata
+                    .data
+                    .creators
+                    .as_ref()
+                    .ok_or(ErrorCode::NoCreators)?;
+                let mut matching_creators = creators
+                    .iter()
+                    .filter(|creator| creator.address == key_to_match)
+                    .peekable();
+                if matching_creators.peek().is_none() {
+                    msg!("No metadata creator matches membership token");
+                    return Err(ErrorCode::MembershipTokenMismatch.into());
+                }
+
+                // as above, only verified creators are allowed
+                if !matching_creators.any(|creator| creator.verified) {
                     msg!("NFT creator is not verified");
                     return Err(ErrorCode::UnverifiedMembershipTokenCreator.into());
                 }
             }
         },
+        Strategy::MembershipNftCollection => match requirement.membership_token {
+            None => return Err(ErrorCode::InvalidStrategy.into()),
+            Some(key_to_match) => {
+                let collection = metadata
+                    .collection
+                    .as_ref()
+                    .ok_or(ErrorCode::NoCollection)?;
+                if collection.key != key_to_match {
+                    msg!("Metadata collection does not match membership token");
+                    return Err(ErrorCode::MembershipTokenMismatch.into());
+                }
+
+                // anyone can mint an NFT claiming to belong to a collection,
+                // but only the collection's update authority can verify it.
+                if !collection.verified {
+                    msg!("NFT collection is not verified");
+                    return Err(ErrorCode::UnverifiedMembershipTokenCollection.into());
+                }
+            }
+        },
         _ => {}
     }
 
@@ -7,6 +7,7 @@ use {
             program_pack::{IsInitialized, Pack},
         },
     },
+    spl_token::instruction::AuthorityType,
 };
 
 pub fn assert_initialized<T: Pack + IsInitialized>(
@@ -69,3 +70,126 @@ pub fn spl_token_mint(params: TokenMintParams<'_, '_>) -> ProgramResult {
 
     result.map_err(|_| ErrorCode::TokenTransferFailed.into())
 }
+
+/// Parameters for an SPL Token transfer CPI
+pub struct TokenTransferParams<'a: 'b, 'b> {
+    /// the source token account
+    pub source: AccountInfo<'a>,
+    /// the destination token account
+    pub destination: AccountInfo<'a>,
+    /// the amount of tokens to transfer
+    pub amount: u64,
+    /// the owner of the source account
+    pub authority: AccountInfo<'a>,
+    /// if the source authority is a PDA, the signer seeds for the account
+    pub authority_signer_seeds: &'b [&'b [u8]],
+    /// the SPL Token program
+    pub token_program: AccountInfo<'a>,
+}
+
+pub fn spl_token_transfer(params: TokenTransferParams<'_, '_>) -> ProgramResult {
+    let TokenTransferParams {
+        source,
+        destination,
+        authority,
+        token_program,
+        amount,
+        authority_signer_seeds,
+    } = params;
+
+    let result = invoke_signed(
+        &spl_token::instruction::transfer(
+            token_program.key,
+            source.key,
+            destination.key,
+            authority.key,
+            &[],
+            amount,
+        )?,
+        &[source, destination, authority, token_program],
+        &[authority_signer_seeds],
+    );
+
+    result.map_err(|_| ErrorCode::TokenTransferFailed.into())
+}
+
+/// Parameters for an SPL Token burn CPI
+pub struct TokenBurnParams<'a: 'b, 'b> {
+    /// the token account to burn from
+    pub source: AccountInfo<'a>,
+    /// the mint of the token being burned
+    pub mint: AccountInfo<'a>,
+    /// the amount of tokens to burn
+    pub amount: u64,
+    /// the owner or delegate of the source account
+    pub authority: AccountInfo<'a>,
+    /// if the source authority is a PDA, the signer seeds for the account
+    pub authority_signer_seeds: &'b [&'b [u8]],
+    /// the SPL Token program
+    pub token_program: AccountInfo<'a>,
+}
+
+pub fn spl_token_burn(params: TokenBurnParams<'_, '_>) -> ProgramResult {
+    let TokenBurnParams {
+        source,
+        mint,
+        authority,
+        token_program,
+        amount,
+        authority_signer_seeds,
+    } = params;
+
+    let result = invoke_signed(
+        &spl_token::instruction::burn(
+            token_program.key,
+            source.key,
+            mint.key,
+            authority.key,
+            &[],
+            amount,
+        )?,
+        &[source, mint, authority, token_program],
+        &[authority_signer_seeds],
+    );
+
+    result.map_err(|_| ErrorCode::TokenBurnFailed.into())
+}
+
+/// Parameters for a CPI changing the mint authority of an SPL Token mint
+pub struct TokenSetAuthorityParams<'a: 'b, 'b> {
+    /// the token mint
+    pub mint: AccountInfo<'a>,
+    /// the new mint authority, or None to disable minting permanently
+    pub new_authority: Option<&'b Pubkey>,
+    /// the current mint authority
+    pub mint_authority: AccountInfo<'a>,
+    /// if the current mint authority is a PDA, the signer seeds for the account
+    pub authority_signer_seeds: &'b [&'b [u8]],
+    /// the SPL Token program
+    pub token_program: AccountInfo<'a>,
+}
+
+pub fn spl_token_set_mint_authority(params: TokenSetAuthorityParams<'_, '_>) -> ProgramResult {
+    let TokenSetAuthorityParams {
+        mint,
+        new_authority,
+        mint_authority,
+        authority_signer_seeds,
+        token_program,
+    } = params;
+
+    let result = invoke_signed(
+        &spl_token::instruction::set_authority(
+            token_program.key,
+            mint.key,
+            new_authority,
+            AuthorityType::MintTokens,
+            mint_authority.key,
+            &[],
+        )?,
+        &[mint, mint_authority, token_program],
+        &[authority_signer_seeds],
+    );
+
+    result.map_err(|_| ErrorCode::SetAuthorityFailed.into())
+}
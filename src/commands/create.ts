@@ -5,10 +5,21 @@ import {
   clusterFlag,
   gatekeeperNetworkPubkeyFlag,
   startTimeFlag,
+  endTimeFlag,
   recipientPubkeyFlag,
   membershipTokenFlag,
   membershipTokenStrategyFlag,
+  allowDelegateFlag,
+  creatorIndexFlag,
+  requireMasterEditionFlag,
+  minMembershipBalanceFlag,
+  burnMembershipTokenFlag,
+  collectMembershipTokenFlag,
+  membershipConsumeAmountFlag,
   allowanceFlag,
+  inMintFlag,
+  exchangeRateFlag,
+  fixedPriceFlag,
 } from "../lib/cli/flags";
 import { fetchProgram, MembershipToken, Strategy } from "../lib/util";
 import { getProvider } from "../lib/cli/utils";
@@ -16,12 +27,26 @@ import { getProvider } from "../lib/cli/utils";
 const getMembershipTokenFromFlags = (flags: {
   membershipToken?: web3.PublicKey;
   strategy?: Strategy;
+  allowDelegate?: boolean;
+  creatorIndex?: number;
+  requireMasterEdition?: boolean;
+  minMembershipBalance?: number;
+  burnMembershipToken?: boolean;
+  collectMembershipToken?: boolean;
+  membershipConsumeAmount?: number;
 }): MembershipToken | undefined => {
   if (!flags.membershipToken) return undefined;
 
   return {
     key: flags.membershipToken,
     strategy: flags.strategy || "SPL",
+    allowDelegate: flags.allowDelegate,
+    creatorIndex: flags.creatorIndex,
+    requireMasterEdition: flags.requireMasterEdition,
+    minBalance: flags.minMembershipBalance,
+    burn: flags.burnMembershipToken,
+    collect: flags.collectMembershipToken,
+    consumeAmount: flags.membershipConsumeAmount,
   };
 };
 
@@ -54,9 +79,23 @@ $ token-guard create -h
     gatekeeperNetwork: gatekeeperNetworkPubkeyFlag(),
     cluster: clusterFlag(),
     startTime: startTimeFlag(),
+    endTime: endTimeFlag(),
     membershipToken: membershipTokenFlag(),
     strategy: membershipTokenStrategyFlag(),
+    allowDelegate: allowDelegateFlag,
+    creatorIndex: creatorIndexFlag,
+    requireMasterEdition: requireMasterEditionFlag,
+    minMembershipBalance: minMembershipBalanceFlag,
+    burnMembershipToken: burnMembershipTokenFlag,
+    collectMembershipToken: collectMembershipTokenFlag,
+    membershipConsumeAmount: membershipConsumeAmountFlag,
     allowance: allowanceFlag,
+    inMint: inMintFlag(),
+    exchangeRate: exchangeRateFlag(),
+    fixedPrice: fixedPriceFlag,
+    minAmount: flags.integer({
+      description: "The minimum transaction amount (default no limit)",
+    }),
     maxAmount: flags.integer({
       char: "m",
       description: "The maximum transaction amount (default no limit)",
@@ -80,7 +119,12 @@ $ token-guard create -h
       flags.startTime,
       flags.allowance,
       flags.maxAmount,
-      membershipToken
+      membershipToken,
+      flags.endTime,
+      flags.inMint,
+      flags.exchangeRate,
+      flags.fixedPrice,
+      flags.minAmount
     );
 
     this.log(
@@ -93,6 +137,7 @@ Additional Details:
 GatekeeperNetwork: ${tokenGuardState.gatekeeperNetwork}
 Recipient: ${tokenGuardState.recipient}
 MintAuthority: ${tokenGuardState.mintAuthority}
+${tokenGuardState.inMint ? `InMint: ${tokenGuardState.inMint}` : ""}
 ${
   tokenGuardState.membershipToken
     ? `MembershipToken: ${tokenGuardState.membershipToken.key}
@@ -1,7 +1,20 @@
 export { initialize } from "./lib/initialize";
 export { exchange } from "./lib/exchange";
+export { updateGuard } from "./lib/update";
+export { proposeAuthority, acceptAuthority } from "./lib/authority";
+export { closeGuard } from "./lib/close";
+export { pause, resume } from "./lib/pause";
+export { setPhases } from "./lib/phases";
+export {
+  setMembershipRequirements,
+  setMembershipAlternatives,
+} from "./lib/membership";
+export { withdrawCollected } from "./lib/withdraw";
 export {
   TokenGuardState,
+  PhaseConfig,
+  ExchangeRate,
+  RecipientShare,
   fetchProgram,
   getTokenGuardState,
   getTokenWallet,
@@ -0,0 +1,34 @@
+import * as anchor from "@project-serum/anchor";
+import { Program } from "@project-serum/anchor";
+import { TokenGuard } from "../../target/types/token_guard";
+
+/**
+ * Nominate a new authority for the tokenGuard. The authority is only transferred
+ * once the nominated key calls acceptAuthority.
+ * Pass null to withdraw a pending proposal.
+ */
+export const proposeAuthority = async (
+  program: Program<TokenGuard>,
+  provider: anchor.Provider,
+  tokenGuard: anchor.web3.PublicKey,
+  pendingAuthority: anchor.web3.PublicKey | null
+): Promise<string> =>
+  program.rpc.proposeAuthority(pendingAuthority, {
+    accounts: {
+      tokenGuard,
+      authority: provider.wallet.publicKey,
+    },
+  });
+
+export const acceptAuthority = async (
+  program: Program<TokenGuard>,
+  tokenGuard: anchor.web3.PublicKey,
+  pendingAuthority: anchor.web3.Keypair
+): Promise<string> =>
+  program.rpc.acceptAuthority({
+    accounts: {
+      tokenGuard,
+      pendingAuthority: pendingAuthority.publicKey,
+    },
+    signers: [pendingAuthority],
+  });
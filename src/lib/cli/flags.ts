@@ -2,6 +2,7 @@ import { web3 } from "@project-serum/anchor";
 
 import { flags } from "@oclif/command";
 import {
+  ExchangeRate,
   ExtendedCluster,
   getClusterUrl,
   parseStrategy,
@@ -38,6 +39,12 @@ If this key is an SPL-Token mint, the user must present a token account with a b
 Otherwise, the key is assumed to identify the NFT collection, and the user must present a token account from the same collection.`,
 });
 
+export const inMintFlag = flags.build<web3.PublicKey>({
+  char: "i",
+  parse: (pubkey: string) => new web3.PublicKey(pubkey),
+  description: `An optional SPL token mint (e.g. wrapped SOL) that the TokenGuard accepts as payment instead of SOL.`,
+});
+
 export const membershipTokenStrategyFlag = flags.build<Strategy>({
   char: "s",
   parse: (strategy: string) => parseStrategy(strategy),
@@ -48,12 +55,59 @@ export const membershipTokenStrategyFlag = flags.build<Strategy>({
     return undefined;
   },
   dependsOn: ["membershipToken"],
-  options: ["SPL", "NFT-Creator"],
+  options: [
+    "SPL",
+    "NFT-Creator",
+    "NFT-Any-Creator",
+    "NFT-Collection",
+  ],
   description: `If presenting a membership token, the strategy to use to validate the token.
 If the token is an NFT, the presented token must belong to the same collection.
-The NFT collection is defined by the first creator in the metadata.`,
+With NFT-Creator, the NFT collection is defined by the first creator in the metadata (see --creatorIndex).
+With NFT-Any-Creator, it is defined by any verified creator in the metadata.
+With NFT-Collection, it is defined by the verified collection in the metadata.`,
+});
+
+export const allowDelegateFlag = flags.boolean({
+  dependsOn: ["membershipToken"],
+  description: `Accept membership tokens held in a token account delegated to the buyer, rather than owned by them.
+Useful for custodial or staking setups.`,
+});
+
+export const creatorIndexFlag: IOptionFlag<number | undefined> =
+  flags.integer({
+    dependsOn: ["membershipToken"],
+    description: `With the NFT-Creator strategy, the position of the creator in the metadata creators array to match (default 0)`,
+  });
+
+export const requireMasterEditionFlag = flags.boolean({
+  dependsOn: ["membershipToken"],
+  description: `With the NFT strategies, only accept NFTs that have a master edition (i.e. not prints or NFTs without editions)`,
+});
+
+export const minMembershipBalanceFlag: IOptionFlag<number | undefined> =
+  flags.integer({
+    dependsOn: ["membershipToken"],
+    description: `With the SPL strategy, the balance of the membership token (in base units) that a user must hold (default 1)`,
+  });
+
+export const burnMembershipTokenFlag = flags.boolean({
+  dependsOn: ["membershipToken"],
+  description: `Burn the membership token on use, so that it can only be redeemed once`,
 });
 
+export const collectMembershipTokenFlag = flags.boolean({
+  dependsOn: ["membershipToken"],
+  exclusive: ["burnMembershipToken"],
+  description: `Collect the membership token on use into a vault owned by the tokenGuard, from which the authority can withdraw it`,
+});
+
+export const membershipConsumeAmountFlag: IOptionFlag<number | undefined> =
+  flags.integer({
+    dependsOn: ["membershipToken"],
+    description: `The amount of the membership token (in base units) burned or collected on use (default the minimum balance, or one base unit, of an SPL token, or the whole NFT)`,
+  });
+
 export const allowanceFlag: IOptionFlag<number | undefined> = flags.integer({
   char: "a",
   description: `The number of times a buyer can use this tokenGuard (default no limit)`,
@@ -78,3 +132,29 @@ export const startTimeFlag = flags.build<number>({
   },
   description: "An optional timestamp at which to enable the token guard",
 });
+
+export const endTimeFlag = flags.build<number>({
+  char: "e",
+  parse: (timestamp: string) => parseInt(timestamp, 10),
+  description:
+    "An optional timestamp after which the token guard no longer allows exchanges",
+});
+
+export const exchangeRateFlag = flags.build<ExchangeRate>({
+  char: "x",
+  parse: (rate: string) => {
+    const [numerator, denominator] = rate
+      .split("/")
+      .map((n) => parseInt(n, 10));
+    if (!numerator || !denominator) {
+      throw new Error(`Invalid exchange rate: ${rate}`);
+    }
+    return { numerator, denominator };
+  },
+  description: `The number of out tokens minted per lamport (or input token), as a fraction, e.g. 1/1000000000 (default 1/1)`,
+});
+
+export const fixedPriceFlag: IOptionFlag<number | undefined> = flags.integer({
+  char: "p",
+  description: `An optional fixed price (in lamports or input tokens) that every exchange must pay exactly`,
+});
@@ -0,0 +1,45 @@
+import * as anchor from "@project-serum/anchor";
+import { Program } from "@project-serum/anchor";
+import { TOKEN_PROGRAM_ID } from "@solana/spl-token";
+import { TokenGuard } from "../../target/types/token_guard";
+import { deriveMintAuthority, deriveVaultAuthority } from "./util";
+
+/**
+ * Close the tokenGuard, returning its rent to the destination (default: the authority).
+ * The mint authority of the out mint is handed to newMintAuthority,
+ * or removed altogether if newMintAuthority is null.
+ * Fails if any of the vaults of the tokenGuard hold collected membership tokens.
+ */
+export const closeGuard = async (
+  program: Program<TokenGuard>,
+  provider: anchor.Provider,
+  tokenGuard: anchor.web3.PublicKey,
+  newMintAuthority: anchor.web3.PublicKey | null,
+  destination: anchor.web3.PublicKey = provider.wallet.publicKey
+): Promise<string> => {
+  const tokenGuardAccount = await program.account.tokenGuard.fetch(tokenGuard);
+  const [mintAuthority] = await deriveMintAuthority(tokenGuard, program);
+
+  // the program checks that every vault is empty, so pass all of them
+  const [vaultAuthority] = await deriveVaultAuthority(tokenGuard, program);
+  const vaults = await provider.connection.getTokenAccountsByOwner(
+    vaultAuthority,
+    { programId: TOKEN_PROGRAM_ID }
+  );
+
+  return program.rpc.closeGuard(newMintAuthority, {
+    accounts: {
+      tokenGuard,
+      authority: provider.wallet.publicKey,
+      destination,
+      outMint: tokenGuardAccount.outMint,
+      mintAuthority,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    },
+    remainingAccounts: vaults.value.map(({ pubkey }) => ({
+      pubkey,
+      isWritable: false,
+      isSigner: false,
+    })),
+  });
+};
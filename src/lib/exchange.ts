@@ -13,9 +13,15 @@ import { TokenGuard } from "../../target/types/token_guard";
 import {
   deriveAllowanceAccount,
   deriveMintAuthority,
+  deriveVaultAuthority,
+  getActivePhase,
+  getCreateVaultInstruction,
+  getMembershipAccountsLength,
   getRemainingAccounts,
   getTokenWallet,
+  makeMembershipTokenDetails,
   TokenGuardMembershipTokenState,
+  TokenGuardPhasesState,
 } from "./util";
 
 export const exchange = async (
@@ -25,8 +31,13 @@ export const exchange = async (
   sender: anchor.web3.PublicKey,
   payer: anchor.web3.PublicKey,
   gatekeeperNetwork: anchor.web3.PublicKey,
-  amount: number,
-  membershipTokenAccount?: anchor.web3.PublicKey
+  amount?: number, // may be omitted if the tokenGuard has a fixed price
+  membershipTokenAccount?: anchor.web3.PublicKey,
+  // the token accounts of the membership requirements of the tokenGuard, in order
+  additionalMembershipTokenAccounts: anchor.web3.PublicKey[] = [],
+  // the index of the membership alternative of the tokenGuard met by the membership token,
+  // if it does not meet the membership token of the phase
+  membershipAlternative?: number
 ): Promise<TransactionInstruction[]> => {
   const tokenGuardAccount = await program.account.tokenGuard.fetch(tokenGuard);
   const senderAta = await getTokenWallet(sender, tokenGuardAccount.outMint);
@@ -39,6 +50,27 @@ export const exchange = async (
   if (!gatewayToken) throw new Error("Wallet has no gateway token");
 
   const [mintAuthority] = await deriveMintAuthority(tokenGuard, program);
+  const [vaultAuthority] = await deriveVaultAuthority(tokenGuard, program);
+
+  // If the tokenGuard accepts an SPL token instead of SOL,
+  // payment is made between the sender's and recipient's associated token accounts
+  const inMint = tokenGuardAccount.inMint;
+  const payerInTokenAccount = inMint
+    ? await getTokenWallet(sender, inMint)
+    : sender;
+  const getRecipientAccount = async (recipient: anchor.web3.PublicKey) =>
+    inMint ? getTokenWallet(recipient, inMint) : recipient;
+  const recipient = await getRecipientAccount(tokenGuardAccount.recipient);
+
+  // If the payment is split between several recipients,
+  // they are passed in order after the membership token accounts
+  const recipientAccounts: web3.AccountMeta[] = await Promise.all(
+    tokenGuardAccount.recipients.map(async (share) => ({
+      pubkey: await getRecipientAccount(share.recipient),
+      isWritable: true,
+      isSigner: false,
+    }))
+  );
 
   const createATAInstruction = Token.createAssociatedTokenAccountInstruction(
     ASSOCIATED_TOKEN_PROGRAM_ID,
@@ -57,25 +89,97 @@ export const exchange = async (
     []
   );
 
+  // the membership token and allowance requirements depend on the phase of the tokenGuard
+  const tokenGuardPhasesState =
+    tokenGuardAccount as unknown as TokenGuardPhasesState;
+  const { phaseIndex, phase } = getActivePhase(tokenGuardPhasesState);
+
+  // Membership alternatives are accepted in place of the membership token of the phase,
+  // unless the phase requires no membership token
+  const { membershipAlternatives } = tokenGuardPhasesState;
+  const phaseOptions = getMembershipAccountsLength(phase.membership)
+    ? [phase.membership, ...membershipAlternatives]
+    : [phase.membership];
+  const presentedOption =
+    membershipAlternative === undefined
+      ? phase.membership
+      : phaseOptions[membershipAlternative + 1];
+  if (!presentedOption) {
+    throw new Error(
+      `Membership alternative ${membershipAlternative} not found`
+    );
+  }
+
   const remainingAccounts = await getRemainingAccounts(
     connection,
-    tokenGuardAccount as unknown as TokenGuardMembershipTokenState,
-    membershipTokenAccount
+    presentedOption,
+    membershipTokenAccount,
+    vaultAuthority
   );
 
+  // the vaults that membership tokens are collected in are created on first use
+  const createVaultInstructions: TransactionInstruction[] = [];
+  const addCreateVaultInstruction = async (
+    membershipToken: TokenGuardMembershipTokenState,
+    membershipTokenAccounts: web3.AccountMeta[]
+  ) => {
+    const instruction = await getCreateVaultInstruction(
+      connection,
+      membershipToken,
+      membershipTokenAccounts,
+      vaultAuthority,
+      payer
+    );
+    if (instruction) createVaultInstructions.push(instruction);
+  };
+  await addCreateVaultInstruction(presentedOption, remainingAccounts);
+
+  // the program expects room for the accounts of the option needing the most accounts,
+  // so pad the accounts of the presented option with accounts that are not read
+  const phaseAccountsLength = Math.max(
+    ...phaseOptions.map(getMembershipAccountsLength)
+  );
+  const paddingAccounts: web3.AccountMeta[] = [];
+  for (let i = remainingAccounts.length; i < phaseAccountsLength; i++) {
+    paddingAccounts.push({
+      pubkey: web3.SystemProgram.programId,
+      isWritable: false,
+      isSigner: false,
+    });
+  }
+
+  // the accounts for each membership requirement are passed in order after those of the phase
+  const membershipRequirementAccounts: web3.AccountMeta[] = [];
+  const { membershipRequirements } = tokenGuardPhasesState;
+  for (let i = 0; i < membershipRequirements.length; i++) {
+    const accounts = await getRemainingAccounts(
+      connection,
+      membershipRequirements[i],
+      additionalMembershipTokenAccounts[i],
+      vaultAuthority
+    );
+    await addCreateVaultInstruction(membershipRequirements[i], accounts);
+    membershipRequirementAccounts.push(...accounts);
+  }
+
   // If there is a membership token NFT, and an allowance
   // then the allowance is based on that NFT, rather than the user's wallet
   // in other words, the user cannot use the NFT more than x times,
   // Even if the NFT is transferred to another user, it cannot be used again.
   // If there is no membership token NFT, then the allowance is based on the user's wallet.
-  // Note - this line assumes the membership token mint is the second element in the remainingAccounts array
+  // Note - this assumes the mint of an NFT membership token is the second element in the remainingAccounts array
+  const presentedStrategy =
+    makeMembershipTokenDetails(presentedOption)?.strategy;
   const allowanceAccountDeriveKey =
-    remainingAccounts.length > 1 ? remainingAccounts[1].pubkey : sender;
+    presentedStrategy && presentedStrategy !== "SPL"
+      ? remainingAccounts[1].pubkey
+      : sender;
 
   const [allowanceAccount, allowanceAccountBump] = await deriveAllowanceAccount(
     tokenGuard,
     allowanceAccountDeriveKey,
-    program
+    program,
+    phaseIndex === undefined ? undefined : phase.startTime?.toNumber() || 0
   );
 
   console.log({
@@ -86,14 +190,15 @@ export const exchange = async (
   });
 
   const exchangeInstruction = program.instruction.exchange(
-    new BN(amount),
+    amount === undefined ? null : new BN(amount),
     allowanceAccountBump,
     {
       accounts: {
         tokenGuard: tokenGuard,
         payer: sender,
         payerAta: senderAta,
-        recipient: tokenGuardAccount.recipient,
+        payerInTokenAccount,
+        recipient,
         outMint: tokenGuardAccount.outMint,
         mintAuthority,
         gatewayToken: gatewayToken.publicKey,
@@ -103,9 +208,19 @@ export const exchange = async (
         clock: web3.SYSVAR_CLOCK_PUBKEY,
         rent: web3.SYSVAR_RENT_PUBKEY,
       },
-      remainingAccounts,
+      remainingAccounts: [
+        ...remainingAccounts,
+        ...paddingAccounts,
+        ...membershipRequirementAccounts,
+        ...recipientAccounts,
+      ],
     }
   );
 
-  return [createATAInstruction, closeATAInstruction, exchangeInstruction];
+  return [
+    ...createVaultInstructions,
+    createATAInstruction,
+    closeATAInstruction,
+    exchangeInstruction,
+  ];
 };
@@ -2,12 +2,15 @@ import * as anchor from "@project-serum/anchor";
 
 import { MintLayout, Token, TOKEN_PROGRAM_ID } from "@solana/spl-token";
 import { TokenGuard } from "../../target/types/token_guard";
-import { BN, Program, web3 } from "@project-serum/anchor";
+import { Program, web3 } from "@project-serum/anchor";
 import {
+  DEFAULT_EXCHANGE_RATE,
   deriveMintAuthority,
+  ExchangeRate,
   MembershipToken,
-  strategyToInt,
+  RecipientShare,
   TokenGuardState,
+  toGuardPropertiesArg,
 } from "./util";
 
 const DECIMALS = 9; // lamports in 1 sol
@@ -20,7 +23,13 @@ export const initialize = async (
   startTime?: number,
   allowance?: number,
   maxAmount?: number,
-  membershipToken?: MembershipToken
+  membershipToken?: MembershipToken,
+  endTime?: number,
+  inMint?: anchor.web3.PublicKey,
+  exchangeRate: ExchangeRate = DEFAULT_EXCHANGE_RATE,
+  fixedPrice?: number,
+  minAmount?: number,
+  recipientShares: RecipientShare[] = []
 ): Promise<TokenGuardState> => {
   const tokenGuard = web3.Keypair.generate();
   const mint = web3.Keypair.generate();
@@ -28,20 +37,21 @@ export const initialize = async (
     tokenGuard.publicKey,
     program
   );
-  const startTimeBN = startTime ? new BN(startTime) : null;
-  const allowanceOrNull = allowance || null;
-  const maxAmountBN = maxAmount ? new BN(maxAmount) : null;
-  const membershipTokenOrNull = membershipToken?.key || null;
-  const strategyValue = strategyToInt(membershipToken?.strategy);
-
   await program.rpc.initialize(
-    gatekeeperNetwork,
     mintAuthorityBump,
-    startTimeBN,
-    allowanceOrNull,
-    maxAmountBN,
-    membershipTokenOrNull,
-    strategyValue,
+    inMint || null,
+    toGuardPropertiesArg({
+      gatekeeperNetwork,
+      startTime,
+      endTime,
+      allowance,
+      maxAmount,
+      minAmount,
+      fixedPrice,
+      membershipToken,
+      exchangeRate,
+      recipientShares,
+    }),
     {
       accounts: {
         tokenGuard: tokenGuard.publicKey,
@@ -82,5 +92,6 @@ export const initialize = async (
     outMint: mint.publicKey,
     recipient,
     membershipToken,
+    inMint,
   };
 };
@@ -0,0 +1,46 @@
+import * as anchor from "@project-serum/anchor";
+import { Program } from "@project-serum/anchor";
+import { TokenGuard } from "../../target/types/token_guard";
+import { MembershipToken, toMembershipRequirementArg } from "./util";
+
+/**
+ * Require buyers to present all of these membership tokens,
+ * in addition to the membership token of the tokenGuard (or its live phase), if any.
+ * Pass an empty array to remove the requirements.
+ */
+export const setMembershipRequirements = async (
+  program: Program<TokenGuard>,
+  provider: anchor.Provider,
+  tokenGuard: anchor.web3.PublicKey,
+  membershipTokens: MembershipToken[]
+): Promise<string> =>
+  program.rpc.setMembershipRequirements(
+    membershipTokens.map(toMembershipRequirementArg),
+    {
+      accounts: {
+        tokenGuard,
+        authority: provider.wallet.publicKey,
+      },
+    }
+  );
+
+/**
+ * Accept any one of these membership tokens in place of the membership token
+ * of the tokenGuard (or its live phase), e.g. NFTs from several partner collections.
+ * Pass an empty array to remove the alternatives.
+ */
+export const setMembershipAlternatives = async (
+  program: Program<TokenGuard>,
+  provider: anchor.Provider,
+  tokenGuard: anchor.web3.PublicKey,
+  membershipTokens: MembershipToken[]
+): Promise<string> =>
+  program.rpc.setMembershipAlternatives(
+    membershipTokens.map(toMembershipRequirementArg),
+    {
+      accounts: {
+        tokenGuard,
+        authority: provider.wallet.publicKey,
+      },
+    }
+  );
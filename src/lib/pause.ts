@@ -0,0 +1,30 @@
+import * as anchor from "@project-serum/anchor";
+import { Program } from "@project-serum/anchor";
+import { TokenGuard } from "../../target/types/token_guard";
+
+/**
+ * Stop all exchanges through the tokenGuard until it is resumed.
+ */
+export const pause = async (
+  program: Program<TokenGuard>,
+  provider: anchor.Provider,
+  tokenGuard: anchor.web3.PublicKey
+): Promise<string> =>
+  program.rpc.pause({
+    accounts: {
+      tokenGuard,
+      authority: provider.wallet.publicKey,
+    },
+  });
+
+export const resume = async (
+  program: Program<TokenGuard>,
+  provider: anchor.Provider,
+  tokenGuard: anchor.web3.PublicKey
+): Promise<string> =>
+  program.rpc.resume({
+    accounts: {
+      tokenGuard,
+      authority: provider.wallet.publicKey,
+    },
+  });
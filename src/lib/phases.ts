@@ -0,0 +1,33 @@
+import * as anchor from "@project-serum/anchor";
+import { BN, Program } from "@project-serum/anchor";
+import { TokenGuard } from "../../target/types/token_guard";
+import { PhaseConfig, toMembershipRequirementArg } from "./util";
+
+const toPhaseArg = (phase: PhaseConfig) => ({
+  startTime: phase.startTime ? new BN(phase.startTime) : null,
+  endTime: phase.endTime ? new BN(phase.endTime) : null,
+  membership: toMembershipRequirementArg(phase.membershipToken),
+  allowance: phase.allowance || 0,
+  maxAmount: phase.maxAmount ? new BN(phase.maxAmount) : null,
+  minAmount: phase.minAmount ? new BN(phase.minAmount) : null,
+  fixedPrice: phase.fixedPrice ? new BN(phase.fixedPrice) : null,
+});
+
+/**
+ * Replace the sale schedule of the tokenGuard.
+ * While a tokenGuard has phases, the properties of the live phase are used
+ * instead of the top-level start time, end time, allowance, amount limits, price and membership token.
+ * Pass an empty array to remove the phases.
+ */
+export const setPhases = async (
+  program: Program<TokenGuard>,
+  provider: anchor.Provider,
+  tokenGuard: anchor.web3.PublicKey,
+  phases: PhaseConfig[]
+): Promise<string> =>
+  program.rpc.setPhases(phases.map(toPhaseArg), {
+    accounts: {
+      tokenGuard,
+      authority: provider.wallet.publicKey,
+    },
+  });
@@ -0,0 +1,81 @@
+import * as anchor from "@project-serum/anchor";
+import { BN, Program } from "@project-serum/anchor";
+import { TokenGuard } from "../../target/types/token_guard";
+import {
+  ExchangeRate,
+  makeMembershipTokenDetails,
+  MembershipToken,
+  RecipientShare,
+  TokenGuardMembershipTokenState,
+  toGuardPropertiesArg,
+} from "./util";
+
+// an omitted (undefined) property keeps its current value, null unsets it
+const orCurrent = <T>(
+  value: T | null | undefined,
+  current: T | undefined
+): T | undefined => {
+  if (value === undefined) return current;
+  return value === null ? undefined : value;
+};
+
+const toNumber = (value: BN | null): number | undefined =>
+  value ? value.toNumber() : undefined;
+
+/**
+ * Update the properties of the tokenGuard.
+ * Omitted properties keep their current values. Pass null to unset a property.
+ */
+export const updateGuard = async (
+  program: Program<TokenGuard>,
+  provider: anchor.Provider,
+  tokenGuard: anchor.web3.PublicKey,
+  gatekeeperNetwork: anchor.web3.PublicKey,
+  recipient: anchor.web3.PublicKey,
+  startTime?: number | null,
+  allowance?: number | null,
+  maxAmount?: number | null,
+  membershipToken?: MembershipToken | null,
+  endTime?: number | null,
+  exchangeRate?: ExchangeRate,
+  fixedPrice?: number | null,
+  minAmount?: number | null,
+  recipientShares?: RecipientShare[]
+): Promise<string> => {
+  // update_guard replaces every property of the tokenGuard,
+  // so fetch the current values of those that are omitted
+  const tokenGuardAccount = await program.account.tokenGuard.fetch(tokenGuard);
+
+  return program.rpc.updateGuard(
+    toGuardPropertiesArg({
+      gatekeeperNetwork,
+      startTime: orCurrent(startTime, toNumber(tokenGuardAccount.startTime)),
+      endTime: orCurrent(endTime, toNumber(tokenGuardAccount.endTime)),
+      // zero is stored for "no allowance"
+      allowance: orCurrent(allowance, tokenGuardAccount.allowance || undefined),
+      maxAmount: orCurrent(maxAmount, toNumber(tokenGuardAccount.maxAmount)),
+      minAmount: orCurrent(minAmount, toNumber(tokenGuardAccount.minAmount)),
+      fixedPrice: orCurrent(fixedPrice, toNumber(tokenGuardAccount.fixedPrice)),
+      membershipToken: orCurrent(
+        membershipToken,
+        // TODO fix anchor types here
+        makeMembershipTokenDetails(
+          tokenGuardAccount.membership as unknown as TokenGuardMembershipTokenState
+        )
+      ),
+      exchangeRate: exchangeRate || {
+        numerator: tokenGuardAccount.rateNumerator.toNumber(),
+        denominator: tokenGuardAccount.rateDenominator.toNumber(),
+      },
+      recipientShares:
+        recipientShares || (tokenGuardAccount.recipients as RecipientShare[]),
+    }),
+    {
+      accounts: {
+        tokenGuard,
+        authority: provider.wallet.publicKey,
+        recipient,
+      },
+    }
+  );
+};
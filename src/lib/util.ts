@@ -1,11 +1,12 @@
 import * as anchor from "@project-serum/anchor";
-import { Program, Provider, web3 } from "@project-serum/anchor";
+import { BN, Program, Provider, web3 } from "@project-serum/anchor";
 import { AccountInfo, Token, TOKEN_PROGRAM_ID } from "@solana/spl-token";
 import { TokenGuard } from "../../target/types/token_guard";
 import { Cluster, clusterApiUrl } from "@solana/web3.js";
 import { programs } from "@metaplex/js";
 
 const Metadata = programs.metadata.Metadata;
+const MasterEdition = programs.metadata.MasterEdition;
 
 export type ExtendedCluster = Cluster | "localnet" | "civicnet";
 export const CIVICNET_URL =
@@ -37,16 +38,68 @@ export interface TokenGuardState {
   mintAuthority: web3.PublicKey;
   gatekeeperNetwork: web3.PublicKey;
   membershipToken?: MembershipToken;
+  // if set, the tokenGuard accepts this SPL token as payment instead of SOL
+  inMint?: web3.PublicKey;
 }
 
-export type Strategy = "SPL" | "NFT-Creator";
+/**
+ * The number of out tokens minted per input token (or lamport) is numerator / denominator.
+ * For example, with an out mint with 0 decimals, the rate { numerator: 1, denominator: 1_000_000_000 }
+ * mints one out token per SOL.
+ */
+export type ExchangeRate = {
+  numerator: number;
+  denominator: number;
+};
+export const DEFAULT_EXCHANGE_RATE: ExchangeRate = {
+  numerator: 1,
+  denominator: 1,
+};
+
+/**
+ * A recipient's share of each payment, in basis points.
+ * The shares of all recipients must add up to 10000, and the first recipient
+ * must be the recipient of the tokenGuard. It receives any rounding dust.
+ */
+export type RecipientShare = {
+  recipient: web3.PublicKey;
+  bps: number;
+};
+
+export type Strategy =
+  | "SPL"
+  | "NFT-Creator"
+  | "NFT-Any-Creator"
+  | "NFT-Collection";
 export type MembershipToken = {
   key: web3.PublicKey;
   strategy: Strategy;
+  // if set, the membership token may be held in an account delegated to the buyer,
+  // rather than owned by them, e.g. for custodial or staking setups
+  allowDelegate?: boolean;
+  // with the NFT-Creator strategy, the position of the creator to match in the metadata (default 0)
+  creatorIndex?: number;
+  // with the NFT strategies, require the NFT to have a master edition
+  requireMasterEdition?: boolean;
+  // with the SPL strategy, the balance of the membership token required (default 1)
+  minBalance?: number;
+  // if set, the membership token is burned on use, so it can only be redeemed once
+  burn?: boolean;
+  // if set, the membership token is collected on use into a vault owned by the tokenGuard,
+  // from which the authority can withdraw it
+  collect?: boolean;
+  // the amount of the membership token burned or collected on use
+  // (default the minimum balance, or one base unit, of an SPL token, or the whole NFT)
+  consumeAmount?: number;
 };
 
 const isValidStrategy = (strategy: string): boolean =>
-  ["SPL", "NFT-Creator"].includes(strategy);
+  [
+    "SPL",
+    "NFT-Creator",
+    "NFT-Any-Creator",
+    "NFT-Collection",
+  ].includes(strategy);
 
 export const parseStrategy = (strategy: string): Strategy => {
   if (!isValidStrategy(strategy)) {
@@ -55,23 +108,71 @@ export const parseStrategy = (strategy: string): Strategy => {
   return strategy as Strategy;
 };
 
-// should match the Strategy enum in lib.rs
-// TODO Can anchor generate this mapping?
-export const strategyToInt = (strategy?: Strategy): number => {
+// Anchor encodes enums nested in structs as an object with a single camelCase property
+// (see structToStrategy)
+export const strategyToStruct = (strategy?: Strategy): Record<string, {}> => {
   if (!strategy) {
-    return 0;
+    return { gatewayOnly: {} };
   }
 
   switch (strategy) {
     case "SPL":
-      return 1;
+      return { membershipSplToken: {} };
     case "NFT-Creator":
-      return 2;
+      return { membershipNftCreator: {} };
+    case "NFT-Collection":
+      return { membershipNftCollection: {} };
+    case "NFT-Any-Creator":
+      return { membershipNftAnyCreator: {} };
     default:
       throw new Error(`Unknown strategy: ${strategy}`);
   }
 };
 
+// should match the MembershipRequirement struct in lib.rs,
+// whose fields are also the membership token properties of a Phase
+export const toMembershipRequirementArg = (
+  membershipToken?: MembershipToken
+) => ({
+  strategy: strategyToStruct(membershipToken?.strategy),
+  membershipToken: membershipToken?.key || null,
+  allowMembershipDelegate: membershipToken?.allowDelegate || false,
+  creatorIndex: membershipToken?.creatorIndex || 0,
+  requireMasterEdition: membershipToken?.requireMasterEdition || false,
+  minMembershipBalance: new BN(membershipToken?.minBalance || 0),
+  burnMembershipToken: membershipToken?.burn || false,
+  collectMembershipToken: membershipToken?.collect || false,
+  membershipConsumeAmount: new BN(membershipToken?.consumeAmount || 0),
+});
+
+export type GuardPropertiesConfig = {
+  gatekeeperNetwork: web3.PublicKey;
+  startTime?: number;
+  endTime?: number;
+  allowance?: number;
+  maxAmount?: number;
+  minAmount?: number;
+  fixedPrice?: number;
+  membershipToken?: MembershipToken;
+  exchangeRate: ExchangeRate;
+  recipientShares: RecipientShare[];
+};
+
+// should match GuardProperties in lib.rs
+export const toGuardPropertiesArg = (properties: GuardPropertiesConfig) => ({
+  gatekeeperNetwork: properties.gatekeeperNetwork,
+  startTime: properties.startTime ? new BN(properties.startTime) : null,
+  endTime: properties.endTime ? new BN(properties.endTime) : null,
+  allowance: properties.allowance || null,
+  maxAmount: properties.maxAmount ? new BN(properties.maxAmount) : null,
+  minAmount: properties.minAmount ? new BN(properties.minAmount) : null,
+  fixedPrice: properties.fixedPrice ? new BN(properties.fixedPrice) : null,
+  membership: toMembershipRequirementArg(properties.membershipToken),
+  rateNumerator: new BN(properties.exchangeRate.numerator),
+  rateDenominator: new BN(properties.exchangeRate.denominator),
+  recipients: properties.recipientShares,
+});
+
 // TODO fix with anchor mappings
 const structToStrategy = (strategyValue: any): Strategy | undefined => {
   // Note - anchor maps the enum values to properties (converted to camelCase)
@@ -80,6 +181,10 @@ const structToStrategy = (strategyValue: any): Strategy | undefined => {
   if (strategyValue.hasOwnProperty("membershipSplToken")) return "SPL";
   if (strategyValue.hasOwnProperty("membershipNftCreator"))
     return "NFT-Creator";
+  if (strategyValue.hasOwnProperty("membershipNftCollection"))
+    return "NFT-Collection";
+  if (strategyValue.hasOwnProperty("membershipNftAnyCreator"))
+    return "NFT-Any-Creator";
 
   throw new Error(`Unknown strategy value:` + JSON.stringify(strategyValue));
 };
@@ -98,9 +203,16 @@ export const fetchProgram = async (
   ) as Program<TokenGuard>;
 };
 
-const makeMembershipTokenDetails = ({
+export const makeMembershipTokenDetails = ({
   membershipToken,
   strategy,
+  allowMembershipDelegate,
+  creatorIndex,
+  requireMasterEdition,
+  minMembershipBalance,
+  burnMembershipToken,
+  collectMembershipToken,
+  membershipConsumeAmount,
 }: TokenGuardMembershipTokenState): MembershipToken | undefined => {
   if (!membershipToken || !strategy) {
     return undefined;
@@ -109,6 +221,13 @@ const makeMembershipTokenDetails = ({
   return {
     key: membershipToken,
     strategy: structToStrategy(strategy) as Strategy,
+    allowDelegate: allowMembershipDelegate,
+    creatorIndex,
+    requireMasterEdition,
+    minBalance: minMembershipBalance?.toNumber(),
+    burn: burnMembershipToken,
+    collect: collectMembershipToken,
+    consumeAmount: membershipConsumeAmount?.toNumber(),
   };
 };
 
@@ -136,7 +255,7 @@ export const getTokenGuardState = async (
 
   // TODO fix anchor types here
   const membershipToken = makeMembershipTokenDetails(
-    tokenGuardAccount as unknown as TokenGuardMembershipTokenState
+    tokenGuardAccount.membership as unknown as TokenGuardMembershipTokenState
   );
 
   return {
@@ -146,6 +265,7 @@ export const getTokenGuardState = async (
     mintAuthority,
     gatekeeperNetwork: tokenGuardAccount.gatekeeperNetwork,
     membershipToken,
+    inMint: tokenGuardAccount.inMint || undefined,
   };
 };
 
@@ -170,35 +290,119 @@ export const deriveMintAuthority = async (
   );
 };
 
-export const deriveAllowanceAccount = async (
+// the owner of the vaults that collected membership tokens are transferred into
+export const deriveVaultAuthority = async (
   tokenGuard: web3.PublicKey,
-  allowanceAccountDeriveKey: web3.PublicKey,
   program: Program<TokenGuard>
-) => {
-  return web3.PublicKey.findProgramAddress(
-    [
-      Buffer.from("token_guard_allowance_account"),
-      tokenGuard.toBuffer(),
-      allowanceAccountDeriveKey.toBuffer(),
-    ],
+) =>
+  web3.PublicKey.findProgramAddress(
+    [Buffer.from("token_guard_vault_authority"), tokenGuard.toBuffer()],
     program.programId
   );
+
+// allowances are counted separately in each phase of a tokenGuard with phases,
+// identified by its start time (zero if the first phase has none)
+export const deriveAllowanceAccount = async (
+  tokenGuard: web3.PublicKey,
+  allowanceAccountDeriveKey: web3.PublicKey,
+  program: Program<TokenGuard>,
+  phaseStartTime?: number
+) => {
+  const seeds = [
+    Buffer.from("token_guard_allowance_account"),
+    tokenGuard.toBuffer(),
+    allowanceAccountDeriveKey.toBuffer(),
+  ];
+  if (phaseStartTime !== undefined)
+    seeds.push(new BN(phaseStartTime).toArrayLike(Buffer, "le", 8));
+
+  return web3.PublicKey.findProgramAddress(seeds, program.programId);
 };
 
 export type TokenGuardMembershipTokenState = {
   membershipToken?: web3.PublicKey;
   strategy?: any;
+  allowMembershipDelegate?: boolean;
+  creatorIndex?: number;
+  requireMasterEdition?: boolean;
+  minMembershipBalance?: BN;
+  burnMembershipToken?: boolean;
+  collectMembershipToken?: boolean;
+  membershipConsumeAmount?: BN;
+};
+
+export type PhaseState = {
+  startTime: BN | null;
+  endTime: BN | null;
+  membership: TokenGuardMembershipTokenState;
+};
+
+export type TokenGuardPhasesState = PhaseState & {
+  phases: PhaseState[];
+  membershipRequirements: TokenGuardMembershipTokenState[];
+  membershipAlternatives: TokenGuardMembershipTokenState[];
+};
+
+export type PhaseConfig = {
+  startTime?: number;
+  endTime?: number;
+  allowance?: number;
+  maxAmount?: number;
+  minAmount?: number;
+  fixedPrice?: number;
+  membershipToken?: MembershipToken;
 };
 
+/**
+ * Find the phase of the tokenGuard that is live at the given time (in seconds).
+ * Should match get_active_phase in the program.
+ * If the tokenGuard has no phases, the phaseIndex is undefined and the tokenGuard itself is returned.
+ */
+export const getActivePhase = (
+  tokenGuard: TokenGuardPhasesState,
+  now: number = Math.floor(Date.now() / 1000)
+): { phaseIndex?: number; phase: PhaseState } => {
+  const { phases } = tokenGuard;
+  if (!phases.length) return { phase: tokenGuard };
+
+  const hasStarted = (phase: PhaseState) =>
+    !phase.startTime || now >= phase.startTime.toNumber();
+  const hasEnded = (phase: PhaseState) =>
+    !!phase.endTime && now >= phase.endTime.toNumber();
+
+  let phaseIndex = phases.findIndex(
+    (phase) => hasStarted(phase) && !hasEnded(phase)
+  );
+  if (phaseIndex === -1)
+    phaseIndex = phases.findIndex((phase) => !hasStarted(phase));
+  if (phaseIndex === -1) phaseIndex = phases.length - 1;
+
+  return { phaseIndex, phase: phases[phaseIndex] };
+};
+
+// burning or collecting the membership token writes to its token account
+const isConsumed = (membershipTokenDetails: MembershipToken): boolean =>
+  !!membershipTokenDetails.burn || !!membershipTokenDetails.collect;
+
+// the membership token mint is only passed if the token is burned
 const getRemainingAccountsSPL = (
   membershipTokenDetails: MembershipToken,
   membershipTokenAccount: web3.PublicKey
 ): web3.AccountMeta[] => [
   {
     pubkey: membershipTokenAccount,
-    isWritable: false,
+    isWritable: isConsumed(membershipTokenDetails),
     isSigner: false,
   },
+  ...(membershipTokenDetails.burn
+    ? [
+        {
+          pubkey: membershipTokenDetails.key,
+          isWritable: true,
+          isSigner: false,
+        },
+      ]
+    : []),
 ];
 
 const getRemainingAccountsNFT = async (
@@ -220,15 +424,29 @@ const getRemainingAccountsNFT = async (
   const mint = new web3.PublicKey(mintString);
   const metadata = await Metadata.getPDA(mint);
 
+  // the master edition is only passed if the tokenGuard requires it
+  const masterEditionAccounts: web3.AccountMeta[] =
+    membershipTokenDetails.requireMasterEdition
+      ? [
+          {
+            pubkey: await MasterEdition.getPDA(mint),
+            isWritable: false,
+            isSigner: false,
+          },
+        ]
+      : [];
+
+  // burning the membership token writes to the token account and the mint,
+  // collecting it only to the token account
   return [
     {
       pubkey: membershipTokenAccount,
-      isWritable: false,
+      isWritable: isConsumed(membershipTokenDetails),
       isSigner: false,
     },
     {
       pubkey: mint,
-      isWritable: false,
+      isWritable: !!membershipTokenDetails.burn,
       isSigner: false,
     },
     {
@@ -236,21 +454,43 @@ const getRemainingAccountsNFT = async (
       isWritable: false,
       isSigner: false,
     },
+    ...masterEditionAccounts,
   ];
 };
 
-export const getRemainingAccounts = async (
-  connection: web3.Connection,
-  tokenGuard: TokenGuardMembershipTokenState,
-  membershipTokenAccount?: web3.PublicKey
-): Promise<web3.AccountMeta[]> => {
+/**
+ * The number of membership token accounts passed for a membership token.
+ * Should match membership_accounts_len in the program.
+ */
+export const getMembershipAccountsLength = (
+  tokenGuard: TokenGuardMembershipTokenState
+): number => {
   const membershipTokenDetails = makeMembershipTokenDetails(tokenGuard);
-  if (!membershipTokenDetails) return [];
+  if (!membershipTokenDetails) return 0;
+  // the vault that the membership token is collected in is passed last
+  const vaultLength = membershipTokenDetails.collect ? 1 : 0;
+  // the token account, and the mint if the token is burned
+  if (membershipTokenDetails.strategy === "SPL")
+    return (membershipTokenDetails.burn ? 2 : 1) + vaultLength;
+  // the token account, the mint, the metadata account and the master edition account
+  return (membershipTokenDetails.requireMasterEdition ? 4 : 3) + vaultLength;
+};
 
-  if (!membershipTokenAccount) {
-    throw new Error("Membership token account not found");
-  }
+// the mint of the SPL membership token is its key,
+// that of an NFT is the second of the membership token accounts
+const getMembershipTokenMint = (
+  membershipTokenDetails: MembershipToken,
+  membershipTokenAccounts: web3.AccountMeta[]
+): web3.PublicKey =>
+  membershipTokenDetails.strategy === "SPL"
+    ? membershipTokenDetails.key
+    : membershipTokenAccounts[1].pubkey;
 
+const getMembershipTokenAccounts = async (
+  connection: web3.Connection,
+  membershipTokenDetails: MembershipToken,
+  membershipTokenAccount: web3.PublicKey
+): Promise<web3.AccountMeta[]> => {
   switch (membershipTokenDetails.strategy) {
     case "SPL":
       return getRemainingAccountsSPL(
@@ -258,6 +498,8 @@ export const getRemainingAccounts = async (
         membershipTokenAccount
       );
     case "NFT-Creator":
+    case "NFT-Any-Creator":
+    case "NFT-Collection":
       return getRemainingAccountsNFT(
         connection,
         membershipTokenDetails,
@@ -267,3 +509,68 @@ export const getRemainingAccounts = async (
       throw new Error(`Unknown strategy: ${membershipTokenDetails.strategy}`);
   }
 };
+
+export const getRemainingAccounts = async (
+  connection: web3.Connection,
+  tokenGuard: TokenGuardMembershipTokenState,
+  membershipTokenAccount?: web3.PublicKey,
+  vaultAuthority?: web3.PublicKey
+): Promise<web3.AccountMeta[]> => {
+  const membershipTokenDetails = makeMembershipTokenDetails(tokenGuard);
+  if (!membershipTokenDetails) return [];
+
+  if (!membershipTokenAccount) {
+    throw new Error("Membership token account not found");
+  }
+
+  const membershipTokenAccounts = await getMembershipTokenAccounts(
+    connection,
+    membershipTokenDetails,
+    membershipTokenAccount
+  );
+  if (!membershipTokenDetails.collect) return membershipTokenAccounts;
+
+  // the vault that the membership token is collected in is passed last
+  if (!vaultAuthority) throw new Error("Vault authority not found");
+  const mint = getMembershipTokenMint(
+    membershipTokenDetails,
+    membershipTokenAccounts
+  );
+
+  return [
+    ...membershipTokenAccounts,
+    {
+      pubkey: await getTokenWallet(vaultAuthority, mint),
+      isWritable: true,
+      isSigner: false,
+    },
+  ];
+};
+
+/**
+ * If the membership token is collected, and its vault does not exist yet,
+ * an instruction creating the vault, given the accounts from getRemainingAccounts.
+ */
+export const getCreateVaultInstruction = async (
+  connection: web3.Connection,
+  tokenGuard: TokenGuardMembershipTokenState,
+  membershipTokenAccounts: web3.AccountMeta[],
+  vaultAuthority: web3.PublicKey,
+  payer: web3.PublicKey
+): Promise<web3.TransactionInstruction | undefined> => {
+  const membershipTokenDetails = makeMembershipTokenDetails(tokenGuard);
+  if (!membershipTokenDetails?.collect) return undefined;
+
+  const vault =
+    membershipTokenAccounts[membershipTokenAccounts.length - 1].pubkey;
+  if (await connection.getAccountInfo(vault)) return undefined;
+
+  return Token.createAssociatedTokenAccountInstruction(
+    SPL_ASSOCIATED_TOKEN_ACCOUNT_PROGRAM_ID,
+    TOKEN_PROGRAM_ID,
+    getMembershipTokenMint(membershipTokenDetails, membershipTokenAccounts),
+    vault,
+    vaultAuthority,
+    payer
+  );
+};
@@ -0,0 +1,36 @@
+import * as anchor from "@project-serum/anchor";
+import { BN, Program } from "@project-serum/anchor";
+import { TOKEN_PROGRAM_ID } from "@solana/spl-token";
+import { TokenGuard } from "../../target/types/token_guard";
+import { deriveVaultAuthority, getTokenWallet } from "./util";
+
+/**
+ * Withdraw membership tokens of the given mint collected by the tokenGuard
+ * into the destination token account.
+ * Withdraws everything collected, unless an amount is given.
+ */
+export const withdrawCollected = async (
+  program: Program<TokenGuard>,
+  provider: anchor.Provider,
+  tokenGuard: anchor.web3.PublicKey,
+  mint: anchor.web3.PublicKey,
+  destination: anchor.web3.PublicKey,
+  amount?: number
+): Promise<string> => {
+  const [vaultAuthority] = await deriveVaultAuthority(tokenGuard, program);
+  const vault = await getTokenWallet(vaultAuthority, mint);
+
+  return program.rpc.withdrawCollected(
+    amount === undefined ? null : new BN(amount),
+    {
+      accounts: {
+        tokenGuard,
+        authority: provider.wallet.publicKey,
+        vaultAuthority,
+        vault,
+        destination,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      },
+    }
+  );
+};
@@ -15,9 +15,30 @@ import {
 } from "@identity.com/solana-gatekeeper-lib";
 import { GatewayToken } from "@identity.com/solana-gateway-ts";
 import { DummySpender } from "../target/types/dummy_spender";
-import { exchange, initialize, TokenGuardState } from "../src/";
+import {
+  acceptAuthority,
+  closeGuard,
+  exchange,
+  initialize,
+  pause,
+  proposeAuthority,
+  resume,
+  setMembershipAlternatives,
+  setMembershipRequirements,
+  setPhases,
+  TokenGuardState,
+  updateGuard,
+  withdrawCollected,
+} from "../src/";
+import {
+  deriveAllowanceAccount,
+  DEFAULT_EXCHANGE_RATE,
+  deriveVaultAuthority,
+  getTokenWallet,
+  toGuardPropertiesArg,
+} from "../src/lib/util";
 import { TransactionInstruction } from "@solana/web3.js";
-import { actions } from "@metaplex/js";
+import { actions, programs } from "@metaplex/js";
 import axios from "axios";
 
 const metadataJson = require("./fixtures/dummyMetadata.json");
@@ -106,6 +127,72 @@ describe("token-guard", () => {
     return provider.send(transaction, [sender]);
   };
 
+  /**
+   * Replace an account in the instructions, e.g. to simulate a client
+   * that tries to send the payment somewhere other than the tokenGuard recipient.
+   */
+  const replaceAccount = (
+    instructions: TransactionInstruction[],
+    from: web3.PublicKey,
+    to: web3.PublicKey
+  ) =>
+    instructions.map((instruction) => {
+      instruction.keys = instruction.keys.map((key) =>
+        key.pubkey.equals(from) ? { ...key, pubkey: to } : key
+      );
+      return instruction;
+    });
+
+  /**
+   * Expect a transaction to be rejected with the given error of the token guard program.
+   * Errors from provider.send only contain the error code, so it is looked up in the IDL.
+   */
+  const expectProgramError = (
+    promise: Promise<unknown>,
+    errorName: string
+  ) => {
+    const { code } = program.idl.errors.find(({ name }) => name === errorName);
+    return expect(promise).to.be.rejectedWith(
+      new RegExp(`custom program error: 0x${code.toString(16)}\\b`)
+    );
+  };
+
+  /**
+   * Create an account owned by the dummy spender program, containing the given data.
+   * Used to check that the token guard does not trust accounts forged by other programs.
+   */
+  const createForgedAccount = async (data: Buffer) => {
+    const spenderProgram = anchor.workspace
+      .DummySpender as Program<DummySpender>;
+    const account = web3.Keypair.generate();
+    const lamports =
+      await provider.connection.getMinimumBalanceForRentExemption(data.length);
+    await provider.send(
+      new web3.Transaction().add(
+        web3.SystemProgram.createAccount({
+          fromPubkey: provider.wallet.publicKey,
+          newAccountPubkey: account.publicKey,
+          lamports,
+          space: data.length,
+          programId: spenderProgram.programId,
+        })
+      ),
+      [account]
+    );
+
+    // write in chunks that fit in a transaction
+    const chunkSize = 512;
+    for (let offset = 0; offset < data.length; offset += chunkSize) {
+      await spenderProgram.rpc.writeData(
+        offset,
+        data.slice(offset, offset + chunkSize),
+        { accounts: { account: account.publicKey } }
+      );
+    }
+
+    return account.publicKey;
+  };
+
   /**
    * If the from address has a non-zero balance of the token, send one to the to address.
    * Used to help separate tests from each other - e.g. if two tests share an NFT (for speed etc)
@@ -256,6 +343,74 @@ describe("token-guard", () => {
       // expect(parsedAccountInfo.info.tokenAmount.amount).to.equal(''+exchange_amount)
     });
 
+    it("fails to mint the tokens into a token account not owned by the sender", async () => {
+      const someoneElse = web3.Keypair.generate();
+      const someoneElseAta = await Token.getAssociatedTokenAddress(
+        ASSOCIATED_TOKEN_PROGRAM_ID,
+        TOKEN_PROGRAM_ID,
+        tokenGuardState.outMint,
+        someoneElse.publicKey,
+        true
+      );
+
+      const [, , exchangeInstruction] = await exchange(
+        provider.connection,
+        program,
+        tokenGuardState.id,
+        sender.publicKey,
+        sender.publicKey,
+        gatekeeperNetwork.publicKey,
+        exchangeAmount
+      );
+
+      // an ephemeral token account, but owned by someone else
+      const { blockhash } = await provider.connection.getRecentBlockhash();
+      const transaction = new web3.Transaction({
+        recentBlockhash: blockhash,
+      }).add(
+        Token.createAssociatedTokenAccountInstruction(
+          ASSOCIATED_TOKEN_PROGRAM_ID,
+          TOKEN_PROGRAM_ID,
+          tokenGuardState.outMint,
+          someoneElseAta,
+          someoneElse.publicKey,
+          sender.publicKey
+        ),
+        Token.createCloseAccountInstruction(
+          TOKEN_PROGRAM_ID,
+          someoneElseAta,
+          someoneElse.publicKey,
+          someoneElse.publicKey,
+          []
+        ),
+        ...replaceAccount([exchangeInstruction], senderAta, someoneElseAta)
+      );
+
+      const shouldFail = provider.send(transaction, [sender, someoneElse]);
+      return expectProgramError(shouldFail, "TokenAccountOwnerMismatch");
+    });
+
+    it("fails to exchange with a mint authority other than the tokenGuard's", async () => {
+      const instructions = await exchange(
+        provider.connection,
+        program,
+        tokenGuardState.id,
+        sender.publicKey,
+        sender.publicKey,
+        gatekeeperNetwork.publicKey,
+        exchangeAmount
+      );
+
+      const shouldFail = sendTransactionFromSender(
+        replaceAccount(
+          instructions,
+          tokenGuardState.mintAuthority,
+          web3.Keypair.generate().publicKey
+        )
+      );
+      return expectProgramError(shouldFail, "MintAuthorityMismatch");
+    });
+
     it("spends tokens in a separate program", async () => {
       const spenderProgram = anchor.workspace
         .DummySpender as Program<DummySpender>;
@@ -336,6 +491,54 @@ describe("token-guard", () => {
       );
     });
 
+    it("fails to initialise a tokenGuard that ends before it starts", async () => {
+      const now = Math.floor(Date.now() / 1000);
+      const shouldFail = initialize(
+        program,
+        provider,
+        gatekeeperNetwork.publicKey,
+        recipient.publicKey,
+        now + 1_000,
+        undefined,
+        undefined,
+        undefined,
+        now
+      );
+
+      return expect(shouldFail).to.be.rejectedWith(
+        /The end time must be after the start time/
+      );
+    });
+
+    it("initialises a tokenGuard that has ended", async () => {
+      tokenGuardState = await initialize(
+        program,
+        provider,
+        gatekeeperNetwork.publicKey,
+        recipient.publicKey,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        Math.floor(Date.now() / 1000) - 1_000
+      );
+    });
+
+    it("fails to exchange if the tokenGuard has ended", async () => {
+      const instructions = await exchange(
+        provider.connection,
+        program,
+        tokenGuardState.id,
+        sender.publicKey,
+        sender.publicKey,
+        gatekeeperNetwork.publicKey,
+        exchangeAmount
+      );
+
+      const shouldFail = sendTransactionFromSender(instructions);
+      return expectProgramError(shouldFail, "Ended");
+    });
+
     it("initialises a tokenGuard with an allowance", async () => {
       tokenGuardState = await initialize(
         program,
@@ -371,6 +574,106 @@ describe("token-guard", () => {
       );
     });
 
+    it("fails to exchange with an allowance account from another tokenGuard", async () => {
+      const otherTokenGuardState = await initialize(
+        program,
+        provider,
+        gatekeeperNetwork.publicKey,
+        recipient.publicKey,
+        undefined,
+        2
+      );
+
+      // use up one of the allowance of the other tokenGuard
+      await sendTransactionFromSender(
+        await exchange(
+          provider.connection,
+          program,
+          otherTokenGuardState.id,
+          sender.publicKey,
+          sender.publicKey,
+          gatekeeperNetwork.publicKey,
+          exchangeAmount
+        )
+      );
+
+      const [allowanceAccount] = await deriveAllowanceAccount(
+        tokenGuardState.id,
+        sender.publicKey,
+        program
+      );
+      const [otherAllowanceAccount] = await deriveAllowanceAccount(
+        otherTokenGuardState.id,
+        sender.publicKey,
+        program
+      );
+
+      // the allowance of this tokenGuard is used up,
+      // but the other allowance account still has one exchange left
+      const instructions = await exchange(
+        provider.connection,
+        program,
+        tokenGuardState.id,
+        sender.publicKey,
+        sender.publicKey,
+        gatekeeperNetwork.publicKey,
+        exchangeAmount
+      );
+
+      const shouldFail = sendTransactionFromSender(
+        replaceAccount(instructions, allowanceAccount, otherAllowanceAccount)
+      );
+      return expectProgramError(shouldFail, "AllowanceAccountMismatch");
+    });
+
+    it("fails to exchange with an allowance account derived with a non-canonical bump", async () => {
+      // the allowance of this tokenGuard is used up,
+      // but any other valid bump would derive a fresh allowance account
+      const [allowanceAccount, bump] = await deriveAllowanceAccount(
+        tokenGuardState.id,
+        sender.publicKey,
+        program
+      );
+      const seeds = [
+        Buffer.from("token_guard_allowance_account"),
+        tokenGuardState.id.toBuffer(),
+        sender.publicKey.toBuffer(),
+      ];
+      let otherBump = bump - 1;
+      let otherAllowanceAccount: web3.PublicKey | undefined;
+      while (!otherAllowanceAccount) {
+        try {
+          otherAllowanceAccount = await web3.PublicKey.createProgramAddress(
+            [...seeds, Buffer.from([otherBump])],
+            program.programId
+          );
+        } catch {
+          // the address is on the curve, so try the next bump
+          otherBump--;
+        }
+      }
+
+      const instructions = await exchange(
+        provider.connection,
+        program,
+        tokenGuardState.id,
+        sender.publicKey,
+        sender.publicKey,
+        gatekeeperNetwork.publicKey,
+        exchangeAmount
+      );
+      // the bump is the last argument of the exchange instruction
+      const exchangeInstruction = instructions.find((instruction) =>
+        instruction.programId.equals(program.programId)
+      )!;
+      exchangeInstruction.data[exchangeInstruction.data.length - 1] = otherBump;
+
+      const shouldFail = sendTransactionFromSender(
+        replaceAccount(instructions, allowanceAccount, otherAllowanceAccount)
+      );
+      return expectProgramError(shouldFail, "AllowanceAccountMismatch");
+    });
+
     it("initialises a tokenGuard with a max amount", async () => {
       tokenGuardState = await initialize(
         program,
@@ -417,220 +720,1873 @@ describe("token-guard", () => {
     });
   });
 
-  context("Membership Tokens", () => {
-    context("Membership Token SPL", () => {
-      let membershipTokenMint: Token;
-      // this is often the same entity, but to avoid confusion, we alias here.
-      const membershipTokenMinter = recipient;
+  context("Fixed price", () => {
+    it("initialises a tokenGuard with a fixed price", async () => {
+      tokenGuardState = await initialize(
+        program,
+        provider,
+        gatekeeperNetwork.publicKey,
+        recipient.publicKey,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        exchangeAmount
+      );
+    });
 
-      let senderMembershipTokenATA: web3.PublicKey;
+    it("does not initialise a tokenGuard with a fixed price above the max amount", async () => {
+      const shouldFail = initialize(
+        program,
+        provider,
+        gatekeeperNetwork.publicKey,
+        recipient.publicKey,
+        undefined,
+        undefined,
+        exchangeAmount - 1,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        exchangeAmount
+      );
 
-      it("initialises a tokenGuard with a membership token requirement", async () => {
-        membershipTokenMint = await Token.createMint(
-          provider.connection,
-          membershipTokenMinter,
-          membershipTokenMinter.publicKey,
-          membershipTokenMinter.publicKey,
-          0,
-          TOKEN_PROGRAM_ID
-        );
+      return expect(shouldFail).to.be.rejectedWith(
+        /The fixed price must be within the minimum and maximum amounts/
+      );
+    });
 
-        tokenGuardState = await initialize(
-          program,
-          provider,
-          gatekeeperNetwork.publicKey,
-          recipient.publicKey,
-          undefined,
-          undefined,
-          undefined,
-          {
-            key: membershipTokenMint.publicKey,
-            strategy: "SPL",
-          }
-        );
-      });
+    it("fails to exchange an amount other than the price", async () => {
+      const instructions = await exchange(
+        provider.connection,
+        program,
+        tokenGuardState.id,
+        sender.publicKey,
+        sender.publicKey,
+        gatekeeperNetwork.publicKey,
+        exchangeAmount - 1
+      );
 
-      it("should not let someone without the membership token exchange", async () => {
-        const shouldFail = exchange(
-          provider.connection,
-          program,
-          tokenGuardState.id,
-          sender.publicKey,
-          sender.publicKey,
-          gatekeeperNetwork.publicKey,
-          exchangeAmount
-        );
+      const shouldFail = sendTransactionFromSender(instructions);
+      return expectProgramError(shouldFail, "IncorrectPrice");
+    });
 
-        return expect(shouldFail).to.be.rejectedWith(
-          /Membership token account not found/
-        );
-      });
+    it("exchanges the price without passing an amount", async () => {
+      const instructions = await exchange(
+        provider.connection,
+        program,
+        tokenGuardState.id,
+        sender.publicKey,
+        sender.publicKey,
+        gatekeeperNetwork.publicKey
+      );
 
-      it("should not let someone with an insufficient balance of the membership token exchange", async () => {
-        senderMembershipTokenATA =
-          await membershipTokenMint.createAssociatedTokenAccount(
-            sender.publicKey
-          );
-        const instructions = await exchange(
-          provider.connection,
-          program,
-          tokenGuardState.id,
-          sender.publicKey,
-          sender.publicKey,
-          gatekeeperNetwork.publicKey,
-          exchangeAmount,
-          senderMembershipTokenATA
-        );
+      await sendTransactionFromSender(instructions);
+    });
 
-        // fail, because the account is present, but it is empty
-        const shouldFail = sendTransactionFromSender(instructions);
+    it("fails to exchange less than the min amount", async () => {
+      tokenGuardState = await initialize(
+        program,
+        provider,
+        gatekeeperNetwork.publicKey,
+        recipient.publicKey,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        exchangeAmount
+      );
 
-        return expect(shouldFail).to.be.rejectedWith(
-          /Transaction simulation failed/
-        );
-      });
+      const instructions = await exchange(
+        provider.connection,
+        program,
+        tokenGuardState.id,
+        sender.publicKey,
+        sender.publicKey,
+        gatekeeperNetwork.publicKey,
+        exchangeAmount - 1
+      );
 
-      it("should not let someone that does not own the membership token exchange", async () => {
-        const someRandomMembershipTokenATA =
-          await membershipTokenMint.createAssociatedTokenAccount(
-            web3.Keypair.generate().publicKey
-          );
-        const instructions = await exchange(
-          provider.connection,
-          program,
-          tokenGuardState.id,
-          sender.publicKey,
-          sender.publicKey,
-          gatekeeperNetwork.publicKey,
-          exchangeAmount,
-          someRandomMembershipTokenATA
-        );
+      const shouldFail = sendTransactionFromSender(instructions);
+      return expectProgramError(shouldFail, "MinAmountNotReached");
+    });
+  });
 
-        // fail, because the account is present, but has the wrong owner
-        const shouldFail = sendTransactionFromSender(instructions);
+  context("Exchange rate", () => {
+    it("initialises a tokenGuard that mints one token per thousand lamports", async () => {
+      tokenGuardState = await initialize(
+        program,
+        provider,
+        gatekeeperNetwork.publicKey,
+        recipient.publicKey,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        { numerator: 1, denominator: 1_000 }
+      );
+    });
 
-        return expect(shouldFail).to.be.rejectedWith(
-          /Transaction simulation failed/
-        );
-      });
+    it("fails to exchange an amount that does not convert exactly", async () => {
+      const instructions = await exchange(
+        provider.connection,
+        program,
+        tokenGuardState.id,
+        sender.publicKey,
+        sender.publicKey,
+        gatekeeperNetwork.publicKey,
+        exchangeAmount + 1
+      );
 
-      it("should fail if the wrong membership token account is presented", async () => {
-        const someOtherToken = await Token.createMint(
-          provider.connection,
-          membershipTokenMinter,
-          membershipTokenMinter.publicKey,
-          membershipTokenMinter.publicKey,
-          0,
-          TOKEN_PROGRAM_ID
-        );
-        const someOtherTokenATA =
-          await someOtherToken.createAssociatedTokenAccount(
-            web3.Keypair.generate().publicKey
-          );
+      const shouldFail = sendTransactionFromSender(instructions);
+      return expectProgramError(shouldFail, "InexactExchangeRate");
+    });
 
-        const instructions = await exchange(
-          provider.connection,
-          program,
-          tokenGuardState.id,
-          sender.publicKey,
-          sender.publicKey,
-          gatekeeperNetwork.publicKey,
-          exchangeAmount,
-          someOtherTokenATA
-        );
+    it("exchanges an amount that converts exactly", async () => {
+      const instructions = await exchange(
+        provider.connection,
+        program,
+        tokenGuardState.id,
+        sender.publicKey,
+        sender.publicKey,
+        gatekeeperNetwork.publicKey,
+        exchangeAmount
+      );
 
-        // fail, because the account is present, but is for the wrong token
-        const shouldFail = sendTransactionFromSender(instructions);
+      await sendTransactionFromSender(instructions);
+    });
 
-        return expect(shouldFail).to.be.rejectedWith(
-          /Transaction simulation failed/
-        );
-      });
+    it("keeps the exchange rate when the tokenGuard is updated without one", async () => {
+      await updateGuard(
+        program,
+        provider,
+        tokenGuardState.id,
+        gatekeeperNetwork.publicKey,
+        recipient.publicKey
+      );
 
-      it("should let someone with the token exchange", async () => {
-        await membershipTokenMint.mintTo(
-          senderMembershipTokenATA,
-          membershipTokenMinter,
-          [],
-          1
-        );
+      tokenGuardAccount = await program.account.tokenGuard.fetch(
+        tokenGuardState.id
+      );
+      expect(tokenGuardAccount.rateNumerator.toNumber()).to.equal(1);
+      expect(tokenGuardAccount.rateDenominator.toNumber()).to.equal(1_000);
+    });
 
-        const instructions = await exchange(
-          provider.connection,
-          program,
-          tokenGuardState.id,
-          sender.publicKey,
-          sender.publicKey,
-          gatekeeperNetwork.publicKey,
-          exchangeAmount,
-          senderMembershipTokenATA
-        );
+    it("fails to initialise a tokenGuard with a zero exchange rate", async () => {
+      const shouldFail = initialize(
+        program,
+        provider,
+        gatekeeperNetwork.publicKey,
+        recipient.publicKey,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        { numerator: 1, denominator: 0 }
+      );
 
-        await sendTransactionFromSender(instructions);
-      });
+      return expect(shouldFail).to.be.rejectedWith(
+        /The exchange rate numerator and denominator must be greater than zero/
+      );
     });
+  });
 
-    context("Membership Token NFT", () => {
-      let nft: Token;
-      let mint: web3.PublicKey;
-      let metadata: web3.PublicKey;
-      let minterATA: web3.PublicKey;
-      let senderMembershipTokenATA: web3.PublicKey;
+  context("Recipient", () => {
+    it("fails to exchange if the payment is sent to the wrong recipient", async () => {
+      tokenGuardState = await initialize(
+        program,
+        provider,
+        gatekeeperNetwork.publicKey,
+        recipient.publicKey
+      );
 
-      const nftMinter = web3.Keypair.generate();
-      const nftMinterProvider = new Provider(
+      const instructions = await exchange(
         provider.connection,
-        new anchor.Wallet(nftMinter),
-        {}
+        program,
+        tokenGuardState.id,
+        sender.publicKey,
+        sender.publicKey,
+        gatekeeperNetwork.publicKey,
+        exchangeAmount
       );
 
-      const dummyMetadataUri = "somewhere";
+      // the sender tries to pay themselves
+      const shouldFail = sendTransactionFromSender(
+        replaceAccount(instructions, recipient.publicKey, sender.publicKey)
+      );
+      return expectProgramError(shouldFail, "RecipientMismatch");
+    });
+  });
 
-      before("Mint an NFT", async () => {
-        // the minter needs more funds - is it paying arweave?
-        await fund(nftMinter.publicKey, 2_000_000_000);
+  context("SPL Token payment", () => {
+    let inMint: Token;
+    let recipientInATA: web3.PublicKey;
 
-        // stub axios to look up dummy metadata
-        // the minter has to be in the creators array
-        metadataJson.properties.creators[0].address =
-          nftMinter.publicKey.toBase58();
-        sandbox.stub(axios, "get").resolves({ data: metadataJson });
+    it("initialises a tokenGuard that accepts an SPL token", async () => {
+      inMint = await Token.createMint(
+        provider.connection,
+        recipient,
+        recipient.publicKey,
+        null,
+        0,
+        TOKEN_PROGRAM_ID
+      );
+      const senderInATA = await inMint.createAssociatedTokenAccount(
+        sender.publicKey
+      );
+      recipientInATA = await inMint.createAssociatedTokenAccount(
+        recipient.publicKey
+      );
+      await inMint.mintTo(senderInATA, recipient, [], exchangeAmount);
 
-        const response = await actions.mintNFT({
-          connection: nftMinterProvider.connection,
-          wallet: nftMinterProvider.wallet,
-          uri: dummyMetadataUri,
-          maxSupply: 1,
-        });
+      tokenGuardState = await initialize(
+        program,
+        provider,
+        gatekeeperNetwork.publicKey,
+        recipient.publicKey,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        inMint.publicKey
+      );
 
-        await nftMinterProvider.connection.confirmTransaction(response.txId);
+      tokenGuardAccount = await program.account.tokenGuard.fetch(
+        tokenGuardState.id
+      );
+      expect(tokenGuardAccount.inMint.toString()).to.equal(
+        inMint.publicKey.toString()
+      );
+    });
 
-        mint = response.mint;
-        metadata = response.metadata;
+    it("fails to exchange if the payment is sent to a token account not owned by the recipient", async () => {
+      const senderSecondInTokenAccount = await inMint.createAccount(
+        sender.publicKey
+      );
 
-        nft = new Token(
-          nftMinterProvider.connection,
-          mint,
-          TOKEN_PROGRAM_ID,
-          nftMinter
-        );
+      const instructions = await exchange(
+        provider.connection,
+        program,
+        tokenGuardState.id,
+        sender.publicKey,
+        sender.publicKey,
+        gatekeeperNetwork.publicKey,
+        exchangeAmount
+      );
 
-        console.log("Getting the minter's associated token account");
-        minterATA = await Token.getAssociatedTokenAddress(
-          ASSOCIATED_TOKEN_PROGRAM_ID,
-          TOKEN_PROGRAM_ID,
+      // the sender tries to pay into their own token account
+      const shouldFail = sendTransactionFromSender(
+        replaceAccount(instructions, recipientInATA, senderSecondInTokenAccount)
+      );
+      return expectProgramError(shouldFail, "RecipientMismatch");
+    });
+
+    it("exchanges SPL tokens for tokens", async () => {
+      const instructions = await exchange(
+        provider.connection,
+        program,
+        tokenGuardState.id,
+        sender.publicKey,
+        sender.publicKey,
+        gatekeeperNetwork.publicKey,
+        exchangeAmount
+      );
+
+      await sendTransactionFromSender(instructions);
+
+      const { amount } = await inMint.getAccountInfo(recipientInATA);
+      expect(amount.toNumber()).to.equal(exchangeAmount);
+    });
+
+    it("fails to exchange if the sender does not have enough SPL tokens", async () => {
+      const instructions = await exchange(
+        provider.connection,
+        program,
+        tokenGuardState.id,
+        sender.publicKey,
+        sender.publicKey,
+        gatekeeperNetwork.publicKey,
+        exchangeAmount
+      );
+
+      const shouldFail = sendTransactionFromSender(instructions);
+      return expectProgramError(shouldFail, "NotEnoughTokens");
+    });
+  });
+
+  context("Split payment", () => {
+    const secondRecipient = web3.Keypair.generate();
+
+    before("Fund the second recipient", () => fund(secondRecipient.publicKey));
+
+    it("fails to initialise a tokenGuard with shares that do not add up to 100%", async () => {
+      const shouldFail = initialize(
+        program,
+        provider,
+        gatekeeperNetwork.publicKey,
+        recipient.publicKey,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        [
+          { recipient: recipient.publicKey, bps: 7_000 },
+          { recipient: secondRecipient.publicKey, bps: 2_000 },
+        ]
+      );
+
+      return expect(shouldFail).to.be.rejectedWith(
+        /The recipient shares must add up to 10000 basis points/
+      );
+    });
+
+    it("initialises a tokenGuard that splits payments between two recipients", async () => {
+      tokenGuardState = await initialize(
+        program,
+        provider,
+        gatekeeperNetwork.publicKey,
+        recipient.publicKey,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        undefined,
+        [
+          { recipient: recipient.publicKey, bps: 7_000 },
+          { recipient: secondRecipient.publicKey, bps: 3_000 },
+        ]
+      );
+
+      tokenGuardAccount = await program.account.tokenGuard.fetch(
+        tokenGuardState.id
+      );
+      expect(tokenGuardAccount.recipients).to.have.length(2);
+    });
+
+    it("exchanges, paying both recipients", async () => {
+      const instructions = await exchange(
+        provider.connection,
+        program,
+        tokenGuardState.id,
+        sender.publicKey,
+        sender.publicKey,
+        gatekeeperNetwork.publicKey,
+        exchangeAmount + 1 // does not split exactly - the first recipient gets the dust
+      );
+
+      await sendTransactionFromSender(instructions);
+    });
+
+    it("keeps the recipient shares when the tokenGuard is updated without them", async () => {
+      await updateGuard(
+        program,
+        provider,
+        tokenGuardState.id,
+        gatekeeperNetwork.publicKey,
+        recipient.publicKey
+      );
+
+      tokenGuardAccount = await program.account.tokenGuard.fetch(
+        tokenGuardState.id
+      );
+      expect(tokenGuardAccount.recipients).to.have.length(2);
+    });
+  });
+
+  context("Update", () => {
+    it("initialises a tokenGuard that is not yet live", async () => {
+      tokenGuardState = await initialize(
+        program,
+        provider,
+        gatekeeperNetwork.publicKey,
+        recipient.publicKey,
+        Date.now() + 1_000_000
+      );
+    });
+
+    it("does not let anyone other than the authority update the tokenGuard", async () => {
+      const shouldFail = program.rpc.updateGuard(
+        toGuardPropertiesArg({
+          gatekeeperNetwork: gatekeeperNetwork.publicKey,
+          exchangeRate: DEFAULT_EXCHANGE_RATE,
+          recipientShares: [],
+        }),
+        {
+          accounts: {
+            tokenGuard: tokenGuardState.id,
+            authority: sender.publicKey,
+            recipient: sender.publicKey,
+          },
+          signers: [sender],
+        }
+      );
+
+      return expect(shouldFail).to.be.rejectedWith(
+        /A has.one constraint was violated/
+      );
+    });
+
+    it("does not let the authority set a membership strategy without a membership token", async () => {
+      const properties = toGuardPropertiesArg({
+        gatekeeperNetwork: gatekeeperNetwork.publicKey,
+        exchangeRate: DEFAULT_EXCHANGE_RATE,
+        recipientShares: [],
+      });
+      const shouldFail = program.rpc.updateGuard(
+        {
+          ...properties,
+          membership: {
+            ...properties.membership,
+            membershipToken: null, // no membership token
+            strategy: { membershipSplToken: {} }, // but the SPL strategy
+          },
+        },
+        {
+          accounts: {
+            tokenGuard: tokenGuardState.id,
+            authority: provider.wallet.publicKey,
+            recipient: recipient.publicKey,
+          },
+        }
+      );
+
+      return expect(shouldFail).to.be.rejectedWith(
+        /The strategy does not match the properties of the TokenGuard/
+      );
+    });
+
+    it("does not let the authority set an unknown membership strategy", async () => {
+      const instruction = program.instruction.updateGuard(
+        toGuardPropertiesArg({
+          gatekeeperNetwork: gatekeeperNetwork.publicKey,
+          exchangeRate: DEFAULT_EXCHANGE_RATE,
+          recipientShares: [],
+        }),
+        {
+          accounts: {
+            tokenGuard: tokenGuardState.id,
+            authority: provider.wallet.publicKey,
+            recipient: recipient.publicKey,
+          },
+        }
+      );
+      // the strategy follows the discriminator, the gatekeeper network and six unset options
+      instruction.data[8 + 32 + 6] = 99;
+
+      const shouldFail = provider.send(new web3.Transaction().add(instruction));
+      // the strategy is an enum, so an unknown one fails to deserialize (InstructionDidNotDeserialize)
+      return expect(shouldFail).to.be.rejectedWith(
+        /custom program error: 0x66\b/
+      );
+    });
+
+    it("does not let the authority set an end time equal to the start time", async () => {
+      const time = Math.floor(Date.now() / 1000) + 1_000;
+      const shouldFail = updateGuard(
+        program,
+        provider,
+        tokenGuardState.id,
+        gatekeeperNetwork.publicKey,
+        recipient.publicKey,
+        time,
+        undefined,
+        undefined,
+        undefined,
+        time
+      );
+
+      return expect(shouldFail).to.be.rejectedWith(
+        /The end time must be after the start time/
+      );
+    });
+
+    it("lets the authority bring the start time forward", async () => {
+      await updateGuard(
+        program,
+        provider,
+        tokenGuardState.id,
+        gatekeeperNetwork.publicKey,
+        recipient.publicKey,
+        null,
+        undefined,
+        exchangeAmount
+      );
+
+      tokenGuardAccount = await program.account.tokenGuard.fetch(
+        tokenGuardState.id
+      );
+
+      expect(tokenGuardAccount.startTime).to.be.null;
+      expect(tokenGuardAccount.maxAmount.toNumber()).to.equal(exchangeAmount);
+      expect(tokenGuardAccount.outMint.toString()).to.equal(
+        tokenGuardState.outMint.toString()
+      );
+    });
+
+    it("exchanges once the tokenGuard is live", async () => {
+      const instructions = await exchange(
+        provider.connection,
+        program,
+        tokenGuardState.id,
+        sender.publicKey,
+        sender.publicKey,
+        gatekeeperNetwork.publicKey,
+        exchangeAmount
+      );
+
+      await sendTransactionFromSender(instructions);
+    });
+
+    it("keeps the omitted properties when the tokenGuard is updated", async () => {
+      const membershipTokenKey = web3.Keypair.generate().publicKey;
+      tokenGuardState = await initialize(
+        program,
+        provider,
+        gatekeeperNetwork.publicKey,
+        recipient.publicKey,
+        undefined,
+        2,
+        exchangeAmount,
+        { key: membershipTokenKey, strategy: "SPL", minBalance: 2 }
+      );
+
+      // only move the start time
+      const startTime = Math.floor(Date.now() / 1000) + 1_000;
+      await updateGuard(
+        program,
+        provider,
+        tokenGuardState.id,
+        gatekeeperNetwork.publicKey,
+        recipient.publicKey,
+        startTime
+      );
+
+      tokenGuardAccount = await program.account.tokenGuard.fetch(
+        tokenGuardState.id
+      );
+      expect(tokenGuardAccount.startTime.toNumber()).to.equal(startTime);
+      expect(tokenGuardAccount.allowance).to.equal(2);
+      expect(tokenGuardAccount.maxAmount.toNumber()).to.equal(exchangeAmount);
+      expect(tokenGuardAccount.membership.strategy).to.have.property(
+        "membershipSplToken"
+      );
+      expect(tokenGuardAccount.membership.membershipToken.toString()).to.equal(
+        membershipTokenKey.toString()
+      );
+      expect(
+        tokenGuardAccount.membership.minMembershipBalance.toNumber()
+      ).to.equal(2);
+    });
+  });
+
+  context("Authority transfer", () => {
+    const newAuthority = web3.Keypair.generate();
+
+    it("initialises a new tokenGuard", async () => {
+      tokenGuardState = await initialize(
+        program,
+        provider,
+        gatekeeperNetwork.publicKey,
+        recipient.publicKey
+      );
+    });
+
+    it("proposes a new authority without changing the current one", async () => {
+      await proposeAuthority(
+        program,
+        provider,
+        tokenGuardState.id,
+        newAuthority.publicKey
+      );
+
+      tokenGuardAccount = await program.account.tokenGuard.fetch(
+        tokenGuardState.id
+      );
+
+      expect(tokenGuardAccount.authority.toString()).to.equal(
+        provider.wallet.publicKey.toString()
+      );
+      expect(tokenGuardAccount.pendingAuthority.toString()).to.equal(
+        newAuthority.publicKey.toString()
+      );
+    });
+
+    it("does not let anyone other than the pending authority accept", async () => {
+      const shouldFail = acceptAuthority(program, tokenGuardState.id, sender);
+
+      return expect(shouldFail).to.be.rejectedWith(
+        /The signer is not the pending authority of this TokenGuard/
+      );
+    });
+
+    it("transfers the authority once the pending authority accepts", async () => {
+      await acceptAuthority(program, tokenGuardState.id, newAuthority);
+
+      tokenGuardAccount = await program.account.tokenGuard.fetch(
+        tokenGuardState.id
+      );
+
+      expect(tokenGuardAccount.authority.toString()).to.equal(
+        newAuthority.publicKey.toString()
+      );
+      expect(tokenGuardAccount.pendingAuthority).to.be.null;
+    });
+  });
+
+  context("Close", () => {
+    it("initialises a new tokenGuard", async () => {
+      tokenGuardState = await initialize(
+        program,
+        provider,
+        gatekeeperNetwork.publicKey,
+        recipient.publicKey
+      );
+    });
+
+    it("does not let anyone other than the authority close the tokenGuard", async () => {
+      const shouldFail = program.rpc.closeGuard(sender.publicKey, {
+        accounts: {
+          tokenGuard: tokenGuardState.id,
+          authority: sender.publicKey,
+          destination: sender.publicKey,
+          outMint: tokenGuardState.outMint,
+          mintAuthority: tokenGuardState.mintAuthority,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        },
+        signers: [sender],
+      });
+
+      return expect(shouldFail).to.be.rejectedWith(
+        /A has.one constraint was violated/
+      );
+    });
+
+    it("closes the tokenGuard and hands back the mint authority", async () => {
+      await closeGuard(
+        program,
+        provider,
+        tokenGuardState.id,
+        provider.wallet.publicKey
+      );
+
+      const tokenGuardAccountInfo = await provider.connection.getAccountInfo(
+        tokenGuardState.id
+      );
+      expect(tokenGuardAccountInfo).to.be.null;
+
+      const outMint = new Token(
+        provider.connection,
+        tokenGuardState.outMint,
+        TOKEN_PROGRAM_ID,
+        sender
+      );
+      const mintInfo = await outMint.getMintInfo();
+      expect(mintInfo.mintAuthority?.toString()).to.equal(
+        provider.wallet.publicKey.toString()
+      );
+    });
+  });
+
+  context("Pause", () => {
+    it("initialises a new tokenGuard", async () => {
+      tokenGuardState = await initialize(
+        program,
+        provider,
+        gatekeeperNetwork.publicKey,
+        recipient.publicKey
+      );
+    });
+
+    it("fails to exchange while the tokenGuard is paused", async () => {
+      await pause(program, provider, tokenGuardState.id);
+
+      const instructions = await exchange(
+        provider.connection,
+        program,
+        tokenGuardState.id,
+        sender.publicKey,
+        sender.publicKey,
+        gatekeeperNetwork.publicKey,
+        exchangeAmount
+      );
+
+      const shouldFail = sendTransactionFromSender(instructions);
+      return expectProgramError(shouldFail, "Paused");
+    });
+
+    it("exchanges once the tokenGuard is resumed", async () => {
+      await resume(program, provider, tokenGuardState.id);
+
+      const instructions = await exchange(
+        provider.connection,
+        program,
+        tokenGuardState.id,
+        sender.publicKey,
+        sender.publicKey,
+        gatekeeperNetwork.publicKey,
+        exchangeAmount
+      );
+
+      await sendTransactionFromSender(instructions);
+    });
+  });
+
+  context("Phases", () => {
+    const now = Math.floor(Date.now() / 1000);
+
+    it("initialises a tokenGuard with an ended phase and a live phase", async () => {
+      tokenGuardState = await initialize(
+        program,
+        provider,
+        gatekeeperNetwork.publicKey,
+        recipient.publicKey
+      );
+
+      await setPhases(program, provider, tokenGuardState.id, [
+        { startTime: now - 2_000, endTime: now - 1_000 },
+        { startTime: now - 1_000, endTime: now + 1_000_000, allowance: 1 },
+      ]);
+
+      tokenGuardAccount = await program.account.tokenGuard.fetch(
+        tokenGuardState.id
+      );
+      expect(tokenGuardAccount.phases).to.have.length(2);
+    });
+
+    it("applies the allowance of the live phase", async () => {
+      const instructions = await exchange(
+        provider.connection,
+        program,
+        tokenGuardState.id,
+        sender.publicKey,
+        sender.publicKey,
+        gatekeeperNetwork.publicKey,
+        exchangeAmount
+      );
+
+      await sendTransactionFromSender(instructions);
+
+      const shouldFail = sendTransactionFromSender(instructions);
+      return expectProgramError(shouldFail, "AllowanceExceeded");
+    });
+
+    it("keeps the allowance of a phase when the phases are replaced", async () => {
+      // the live phase moves to a different position in the list
+      await setPhases(program, provider, tokenGuardState.id, [
+        { startTime: now - 3_000, endTime: now - 2_000 },
+        { startTime: now - 2_000, endTime: now - 1_000 },
+        { startTime: now - 1_000, endTime: now + 1_000_000, allowance: 1 },
+      ]);
+
+      const instructions = await exchange(
+        provider.connection,
+        program,
+        tokenGuardState.id,
+        sender.publicKey,
+        sender.publicKey,
+        gatekeeperNetwork.publicKey,
+        exchangeAmount
+      );
+
+      const shouldFail = sendTransactionFromSender(instructions);
+      return expectProgramError(shouldFail, "AllowanceExceeded");
+    });
+
+    it("does not let the authority set overlapping phases", async () => {
+      const shouldFail = setPhases(program, provider, tokenGuardState.id, [
+        { startTime: now - 2_000, endTime: now },
+        { startTime: now - 1_000, endTime: now + 1_000_000 },
+      ]);
+
+      return expect(shouldFail).to.be.rejectedWith(
+        /Each phase must end before the next one starts/
+      );
+    });
+
+    it("fails to exchange between phases", async () => {
+      await setPhases(program, provider, tokenGuardState.id, [
+        { startTime: now - 2_000, endTime: now - 1_000 },
+        { startTime: now + 1_000_000 },
+      ]);
+
+      const instructions = await exchange(
+        provider.connection,
+        program,
+        tokenGuardState.id,
+        sender.publicKey,
+        sender.publicKey,
+        gatekeeperNetwork.publicKey,
+        exchangeAmount
+      );
+
+      const shouldFail = sendTransactionFromSender(instructions);
+      return expectProgramError(shouldFail, "NotLiveYet");
+    });
+  });
+
+  context("Membership Tokens", () => {
+    context("Membership Token SPL", () => {
+      let membershipTokenMint: Token;
+      // this is often the same entity, but to avoid confusion, we alias here.
+      const membershipTokenMinter = recipient;
+
+      let senderMembershipTokenATA: web3.PublicKey;
+
+      it("initialises a tokenGuard with a membership token requirement", async () => {
+        membershipTokenMint = await Token.createMint(
+          provider.connection,
+          membershipTokenMinter,
+          membershipTokenMinter.publicKey,
+          membershipTokenMinter.publicKey,
+          0,
+          TOKEN_PROGRAM_ID
+        );
+
+        tokenGuardState = await initialize(
+          program,
+          provider,
+          gatekeeperNetwork.publicKey,
+          recipient.publicKey,
+          undefined,
+          undefined,
+          undefined,
+          {
+            key: membershipTokenMint.publicKey,
+            strategy: "SPL",
+          }
+        );
+      });
+
+      it("should not let someone without the membership token exchange", async () => {
+        const shouldFail = exchange(
+          provider.connection,
+          program,
+          tokenGuardState.id,
+          sender.publicKey,
+          sender.publicKey,
+          gatekeeperNetwork.publicKey,
+          exchangeAmount
+        );
+
+        return expect(shouldFail).to.be.rejectedWith(
+          /Membership token account not found/
+        );
+      });
+
+      it("should not let someone with an insufficient balance of the membership token exchange", async () => {
+        senderMembershipTokenATA =
+          await membershipTokenMint.createAssociatedTokenAccount(
+            sender.publicKey
+          );
+        const instructions = await exchange(
+          provider.connection,
+          program,
+          tokenGuardState.id,
+          sender.publicKey,
+          sender.publicKey,
+          gatekeeperNetwork.publicKey,
+          exchangeAmount,
+          senderMembershipTokenATA
+        );
+
+        // fail, because the account is present, but it is empty
+        const shouldFail = sendTransactionFromSender(instructions);
+
+        return expect(shouldFail).to.be.rejectedWith(
+          /Transaction simulation failed/
+        );
+      });
+
+      it("should not let someone that does not own the membership token exchange", async () => {
+        const someRandomMembershipTokenATA =
+          await membershipTokenMint.createAssociatedTokenAccount(
+            web3.Keypair.generate().publicKey
+          );
+        const instructions = await exchange(
+          provider.connection,
+          program,
+          tokenGuardState.id,
+          sender.publicKey,
+          sender.publicKey,
+          gatekeeperNetwork.publicKey,
+          exchangeAmount,
+          someRandomMembershipTokenATA
+        );
+
+        // fail, because the account is present, but has the wrong owner
+        const shouldFail = sendTransactionFromSender(instructions);
+
+        return expect(shouldFail).to.be.rejectedWith(
+          /Transaction simulation failed/
+        );
+      });
+
+      it("should fail if the wrong membership token account is presented", async () => {
+        const someOtherToken = await Token.createMint(
+          provider.connection,
+          membershipTokenMinter,
+          membershipTokenMinter.publicKey,
+          membershipTokenMinter.publicKey,
+          0,
+          TOKEN_PROGRAM_ID
+        );
+        const someOtherTokenATA =
+          await someOtherToken.createAssociatedTokenAccount(
+            web3.Keypair.generate().publicKey
+          );
+
+        const instructions = await exchange(
+          provider.connection,
+          program,
+          tokenGuardState.id,
+          sender.publicKey,
+          sender.publicKey,
+          gatekeeperNetwork.publicKey,
+          exchangeAmount,
+          someOtherTokenATA
+        );
+
+        // fail, because the account is present, but is for the wrong token
+        const shouldFail = sendTransactionFromSender(instructions);
+
+        return expect(shouldFail).to.be.rejectedWith(
+          /Transaction simulation failed/
+        );
+      });
+
+      it("should not accept a membership token account forged by another program", async () => {
+        // the layout of an initialized SPL token account holding one membership token for the sender
+        const forgedTokenAccount = await createForgedAccount(
+          Buffer.concat([
+            membershipTokenMint.publicKey.toBuffer(), // mint
+            sender.publicKey.toBuffer(), // owner
+            new BN(1).toArrayLike(Buffer, "le", 8), // amount
+            Buffer.alloc(36), // delegate
+            Buffer.from([1]), // state: initialized
+            Buffer.alloc(12), // is native
+            Buffer.alloc(8), // delegated amount
+            Buffer.alloc(36), // close authority
+          ])
+        );
+
+        const instructions = await exchange(
+          provider.connection,
+          program,
+          tokenGuardState.id,
+          sender.publicKey,
+          sender.publicKey,
+          gatekeeperNetwork.publicKey,
+          exchangeAmount,
+          forgedTokenAccount
+        );
+
+        const shouldFail = sendTransactionFromSender(instructions);
+
+        return expectProgramError(shouldFail, "IncorrectOwner");
+      });
+
+      it("should let someone with the token exchange", async () => {
+        await membershipTokenMint.mintTo(
+          senderMembershipTokenATA,
+          membershipTokenMinter,
+          [],
+          1
+        );
+
+        const instructions = await exchange(
+          provider.connection,
+          program,
+          tokenGuardState.id,
+          sender.publicKey,
+          sender.publicKey,
+          gatekeeperNetwork.publicKey,
+          exchangeAmount,
+          senderMembershipTokenATA
+        );
+
+        await sendTransactionFromSender(instructions);
+      });
+
+      context("held by someone else", () => {
+        const custodian = web3.Keypair.generate();
+        let custodianMembershipTokenATA: web3.PublicKey;
+
+        it("should not let someone present another holder's membership token", async () => {
+          custodianMembershipTokenATA =
+            await membershipTokenMint.createAssociatedTokenAccount(
+              custodian.publicKey
+            );
+          await membershipTokenMint.mintTo(
+            custodianMembershipTokenATA,
+            membershipTokenMinter,
+            [],
+            1
+          );
+
+          const instructions = await exchange(
+            provider.connection,
+            program,
+            tokenGuardState.id,
+            sender.publicKey,
+            sender.publicKey,
+            gatekeeperNetwork.publicKey,
+            exchangeAmount,
+            custodianMembershipTokenATA
+          );
+
+          // fail, because the account has a balance, but is owned by the custodian
+          const shouldFail = sendTransactionFromSender(instructions);
+
+          return expectProgramError(shouldFail, "MembershipTokenOwnerMismatch");
+        });
+
+        it("should not accept a delegated membership token unless the tokenGuard allows it", async () => {
+          await membershipTokenMint.approve(
+            custodianMembershipTokenATA,
+            sender.publicKey,
+            custodian,
+            [],
+            1
+          );
+
+          const instructions = await exchange(
+            provider.connection,
+            program,
+            tokenGuardState.id,
+            sender.publicKey,
+            sender.publicKey,
+            gatekeeperNetwork.publicKey,
+            exchangeAmount,
+            custodianMembershipTokenATA
+          );
+
+          const shouldFail = sendTransactionFromSender(instructions);
+
+          return expectProgramError(shouldFail, "MembershipTokenOwnerMismatch");
+        });
+
+        it("should let someone exchange with a membership token delegated to them", async () => {
+          const delegateTokenGuardState = await initialize(
+            program,
+            provider,
+            gatekeeperNetwork.publicKey,
+            recipient.publicKey,
+            undefined,
+            undefined,
+            undefined,
+            {
+              key: membershipTokenMint.publicKey,
+              strategy: "SPL",
+              allowDelegate: true,
+            }
+          );
+
+          const instructions = await exchange(
+            provider.connection,
+            program,
+            delegateTokenGuardState.id,
+            sender.publicKey,
+            sender.publicKey,
+            gatekeeperNetwork.publicKey,
+            exchangeAmount,
+            custodianMembershipTokenATA
+          );
+
+          await sendTransactionFromSender(instructions);
+        });
+
+        it("should only count the delegated amount towards the minimum balance", async () => {
+          // the custodian holds two membership tokens, but only one is delegated to the sender
+          await membershipTokenMint.mintTo(
+            custodianMembershipTokenATA,
+            membershipTokenMinter,
+            [],
+            1
+          );
+          const delegateTokenGuardState = await initialize(
+            program,
+            provider,
+            gatekeeperNetwork.publicKey,
+            recipient.publicKey,
+            undefined,
+            undefined,
+            undefined,
+            {
+              key: membershipTokenMint.publicKey,
+              strategy: "SPL",
+              allowDelegate: true,
+              minBalance: 2,
+            }
+          );
+
+          const instructions = await exchange(
+            provider.connection,
+            program,
+            delegateTokenGuardState.id,
+            sender.publicKey,
+            sender.publicKey,
+            gatekeeperNetwork.publicKey,
+            exchangeAmount,
+            custodianMembershipTokenATA
+          );
+
+          const shouldFail = sendTransactionFromSender(instructions);
+          return expectProgramError(shouldFail, "NoMembershipToken");
+        });
+      });
+
+      context("with a minimum balance", () => {
+        const minBalance = 1_000;
+
+        it("should not let someone with less than the minimum balance exchange", async () => {
+          tokenGuardState = await initialize(
+            program,
+            provider,
+            gatekeeperNetwork.publicKey,
+            recipient.publicKey,
+            undefined,
+            undefined,
+            undefined,
+            {
+              key: membershipTokenMint.publicKey,
+              strategy: "SPL",
+              minBalance,
+            }
+          );
+
+          // the sender holds one membership token
+          const instructions = await exchange(
+            provider.connection,
+            program,
+            tokenGuardState.id,
+            sender.publicKey,
+            sender.publicKey,
+            gatekeeperNetwork.publicKey,
+            exchangeAmount,
+            senderMembershipTokenATA
+          );
+
+          const shouldFail = sendTransactionFromSender(instructions);
+          return expectProgramError(shouldFail, "NoMembershipToken");
+        });
+
+        it("should let someone with the minimum balance exchange", async () => {
+          await membershipTokenMint.mintTo(
+            senderMembershipTokenATA,
+            membershipTokenMinter,
+            [],
+            minBalance - 1
+          );
+
+          const instructions = await exchange(
+            provider.connection,
+            program,
+            tokenGuardState.id,
+            sender.publicKey,
+            sender.publicKey,
+            gatekeeperNetwork.publicKey,
+            exchangeAmount,
+            senderMembershipTokenATA
+          );
+
+          await sendTransactionFromSender(instructions);
+        });
+      });
+
+      context("burning the membership token", () => {
+        // the sender holds the minimum balance of the previous context
+        const burnAmount = 400;
+
+        const exchangeBurningMembershipToken = async () => {
+          const instructions = await exchange(
+            provider.connection,
+            program,
+            tokenGuardState.id,
+            sender.publicKey,
+            sender.publicKey,
+            gatekeeperNetwork.publicKey,
+            exchangeAmount,
+            senderMembershipTokenATA
+          );
+
+          return sendTransactionFromSender(instructions);
+        };
+
+        it("should burn the membership token on exchange", async () => {
+          tokenGuardState = await initialize(
+            program,
+            provider,
+            gatekeeperNetwork.publicKey,
+            recipient.publicKey,
+            undefined,
+            undefined,
+            undefined,
+            {
+              key: membershipTokenMint.publicKey,
+              strategy: "SPL",
+              burn: true,
+              consumeAmount: burnAmount,
+            }
+          );
+          const balanceBefore = (
+            await membershipTokenMint.getAccountInfo(senderMembershipTokenATA)
+          ).amount.toNumber();
+
+          await exchangeBurningMembershipToken();
+
+          const balanceAfter = (
+            await membershipTokenMint.getAccountInfo(senderMembershipTokenATA)
+          ).amount.toNumber();
+          expect(balanceAfter).to.equal(balanceBefore - burnAmount);
+        });
+
+        it("should not let someone exchange once the membership token is used up", async () => {
+          // the remaining balance covers one more burn
+          await exchangeBurningMembershipToken();
+
+          const shouldFail = exchangeBurningMembershipToken();
+          // the token program rejects burning more than the remaining balance (InsufficientFunds)
+          return expect(shouldFail).to.be.rejectedWith(
+            /custom program error: 0x1\b/
+          );
+        });
+
+        it("should burn one base unit of an SPL membership token by default", async () => {
+          tokenGuardState = await initialize(
+            program,
+            provider,
+            gatekeeperNetwork.publicKey,
+            recipient.publicKey,
+            undefined,
+            undefined,
+            undefined,
+            {
+              key: membershipTokenMint.publicKey,
+              strategy: "SPL",
+              burn: true,
+            }
+          );
+          const balanceBefore = (
+            await membershipTokenMint.getAccountInfo(senderMembershipTokenATA)
+          ).amount.toNumber();
+
+          await exchangeBurningMembershipToken();
+
+          const balanceAfter = (
+            await membershipTokenMint.getAccountInfo(senderMembershipTokenATA)
+          ).amount.toNumber();
+          expect(balanceAfter).to.equal(balanceBefore - 1);
+        });
+      });
+
+      context("collecting the membership token", () => {
+        const collectAmount = 100;
+        let vault: web3.PublicKey;
+
+        it("should collect the membership token into the vault on exchange", async () => {
+          // the balance left by the previous context covers the collect amount
+          tokenGuardState = await initialize(
+            program,
+            provider,
+            gatekeeperNetwork.publicKey,
+            recipient.publicKey,
+            undefined,
+            undefined,
+            undefined,
+            {
+              key: membershipTokenMint.publicKey,
+              strategy: "SPL",
+              collect: true,
+              consumeAmount: collectAmount,
+            }
+          );
+          const [vaultAuthority] = await deriveVaultAuthority(
+            tokenGuardState.id,
+            program
+          );
+          vault = await getTokenWallet(
+            vaultAuthority,
+            membershipTokenMint.publicKey
+          );
+
+          const instructions = await exchange(
+            provider.connection,
+            program,
+            tokenGuardState.id,
+            sender.publicKey,
+            sender.publicKey,
+            gatekeeperNetwork.publicKey,
+            exchangeAmount,
+            senderMembershipTokenATA
+          );
+          await sendTransactionFromSender(instructions);
+
+          const vaultAccount = await membershipTokenMint.getAccountInfo(vault);
+          expect(vaultAccount.amount.toNumber()).to.equal(collectAmount);
+        });
+
+        it("does not let the authority close the tokenGuard while the vault holds collected tokens", async () => {
+          const shouldFail = closeGuard(
+            program,
+            provider,
+            tokenGuardState.id,
+            provider.wallet.publicKey
+          );
+
+          return expect(shouldFail).to.be.rejectedWith(
+            /The TokenGuard cannot be closed while its vaults hold collected membership tokens/
+          );
+        });
+
+        it("does not accept a token account of someone else as a vault when closing", async () => {
+          const shouldFail = program.rpc.closeGuard(provider.wallet.publicKey, {
+            accounts: {
+              tokenGuard: tokenGuardState.id,
+              authority: provider.wallet.publicKey,
+              destination: provider.wallet.publicKey,
+              outMint: tokenGuardState.outMint,
+              mintAuthority: tokenGuardState.mintAuthority,
+              tokenProgram: TOKEN_PROGRAM_ID,
+            },
+            remainingAccounts: [
+              {
+                pubkey: senderMembershipTokenATA,
+                isWritable: false,
+                isSigner: false,
+              },
+            ],
+          });
+
+          return expect(shouldFail).to.be.rejectedWith(
+            /The vault is not the token guard's vault for the membership token mint/
+          );
+        });
+
+        it("lets the authority withdraw the collected membership tokens", async () => {
+          const destination =
+            await membershipTokenMint.getOrCreateAssociatedAccountInfo(
+              recipient.publicKey
+            );
+          const destinationBalanceBefore = destination.amount.toNumber();
+
+          await withdrawCollected(
+            program,
+            provider,
+            tokenGuardState.id,
+            membershipTokenMint.publicKey,
+            destination.address
+          );
+
+          const vaultAccount = await membershipTokenMint.getAccountInfo(vault);
+          expect(vaultAccount.amount.toNumber()).to.equal(0);
+          const destinationAccount = await membershipTokenMint.getAccountInfo(
+            destination.address
+          );
+          expect(destinationAccount.amount.toNumber()).to.equal(
+            destinationBalanceBefore + collectAmount
+          );
+        });
+
+        it("lets the authority close the tokenGuard once the collected tokens are withdrawn", async () => {
+          await closeGuard(
+            program,
+            provider,
+            tokenGuardState.id,
+            provider.wallet.publicKey
+          );
+
+          const tokenGuardAccountInfo =
+            await provider.connection.getAccountInfo(tokenGuardState.id);
+          expect(tokenGuardAccountInfo).to.be.null;
+        });
+      });
+    });
+
+    context("Membership Token NFT", () => {
+      let nft: Token;
+      let mint: web3.PublicKey;
+      let metadata: web3.PublicKey;
+      let minterATA: web3.PublicKey;
+      let senderMembershipTokenATA: web3.PublicKey;
+      // an NFT of the sender whose only verified creator is the second creator
+      let secondCreatorNftATA: web3.PublicKey;
+
+      const nftMinter = web3.Keypair.generate();
+      const nftMinterProvider = new Provider(
+        provider.connection,
+        new anchor.Wallet(nftMinter),
+        {}
+      );
+
+      const dummyMetadataUri = "somewhere";
+
+      before("Mint an NFT", async () => {
+        // the minter needs more funds - is it paying arweave?
+        await fund(nftMinter.publicKey, 2_000_000_000);
+
+        // stub axios to look up dummy metadata
+        // the minter has to be in the creators array
+        metadataJson.properties.creators[0].address =
+          nftMinter.publicKey.toBase58();
+        sandbox.stub(axios, "get").resolves({ data: metadataJson });
+
+        const response = await actions.mintNFT({
+          connection: nftMinterProvider.connection,
+          wallet: nftMinterProvider.wallet,
+          uri: dummyMetadataUri,
+          maxSupply: 1,
+        });
+
+        await nftMinterProvider.connection.confirmTransaction(response.txId);
+
+        mint = response.mint;
+        metadata = response.metadata;
+
+        nft = new Token(
+          nftMinterProvider.connection,
+          mint,
+          TOKEN_PROGRAM_ID,
+          nftMinter
+        );
+
+        console.log("Getting the minter's associated token account");
+        minterATA = await Token.getAssociatedTokenAddress(
+          ASSOCIATED_TOKEN_PROGRAM_ID,
+          TOKEN_PROGRAM_ID,
           mint,
           nftMinter.publicKey
         );
 
-        console.log("Creating the sender's associated token account");
-        senderMembershipTokenATA = await nft.createAssociatedTokenAccount(
-          sender.publicKey
-        );
+        console.log("Creating the sender's associated token account");
+        senderMembershipTokenATA = await nft.createAssociatedTokenAccount(
+          sender.publicKey
+        );
+      });
+
+      context("Creator strategy", () => {
+        it("should initialize a tokenGuard that requires presentation of an NFT", async () => {
+          tokenGuardState = await initialize(
+            program,
+            provider,
+            gatekeeperNetwork.publicKey,
+            recipient.publicKey,
+            undefined,
+            undefined,
+            undefined,
+            {
+              key: nftMinter.publicKey,
+              strategy: "NFT-Creator",
+            }
+          );
+        });
+
+        it("should not let someone without the membership token exchange", async () => {
+          const shouldFail = exchange(
+            provider.connection,
+            program,
+            tokenGuardState.id,
+            sender.publicKey,
+            sender.publicKey,
+            gatekeeperNetwork.publicKey,
+            exchangeAmount
+          );
+
+          return expect(shouldFail).to.be.rejectedWith(
+            /Membership token account not found/
+          );
+        });
+
+        it("should not let someone with a membership token from an unverified creator exchange", async () => {
+          // the sender tries to mint their own NFT
+          // first they add their own address as a creator
+          // but keep the original address as the first creator
+          const bogusMetadataJson = {
+            ...metadataJson,
+            properties: {
+              ...metadataJson.properties,
+              creators: [
+                ...metadataJson.properties.creators,
+                {
+                  address: sender.publicKey.toBase58(),
+                  verified: false,
+                },
+              ],
+            },
+          };
+          sandbox.restore();
+          sandbox.stub(axios, "get").resolves({ data: bogusMetadataJson });
+
+          // next they mint their own NFT (To themselves)
+          await fund(sender.publicKey, 2_000_000_000);
+          const senderProvider = new Provider(
+            provider.connection,
+            new anchor.Wallet(sender),
+            {}
+          );
+          const response = await actions.mintNFT({
+            connection: senderProvider.connection,
+            wallet: senderProvider.wallet,
+            uri: dummyMetadataUri,
+            maxSupply: 1,
+          });
+          await senderProvider.connection.confirmTransaction(response.txId);
+
+          const bogusMint = response.mint;
+
+          const bogusSenderMembershipTokenATA =
+            await Token.getAssociatedTokenAddress(
+              ASSOCIATED_TOKEN_PROGRAM_ID,
+              TOKEN_PROGRAM_ID,
+              bogusMint,
+              sender.publicKey
+            );
+
+          // then they try to use that token to exchange
+          const instructions = await exchange(
+            provider.connection,
+            program,
+            tokenGuardState.id,
+            sender.publicKey,
+            sender.publicKey,
+            gatekeeperNetwork.publicKey,
+            exchangeAmount,
+            bogusSenderMembershipTokenATA
+          );
+
+          const shouldFail = sendTransactionFromSender(instructions);
+
+          return expect(shouldFail).to.be.rejectedWith(
+            /Transaction simulation failed/
+          );
+        });
+
+        it("should let someone with the token exchange", async () => {
+          await checkBalanceAndSend(nft, nftMinter, sender.publicKey);
+
+          const instructions = await exchange(
+            provider.connection,
+            program,
+            tokenGuardState.id,
+            sender.publicKey,
+            sender.publicKey,
+            gatekeeperNetwork.publicKey,
+            exchangeAmount,
+            senderMembershipTokenATA
+          );
+
+          await sendTransactionFromSender(instructions);
+        });
+
+        it("should not accept a fake metadata account owned by a different program", async () => {
+          // an exact copy of the real metadata, so only the owner check can reject it
+          const metadataAccountInfo = await provider.connection.getAccountInfo(
+            metadata
+          );
+          const fakeMetadata = await createForgedAccount(
+            metadataAccountInfo!.data
+          );
+
+          const instructions = await exchange(
+            provider.connection,
+            program,
+            tokenGuardState.id,
+            sender.publicKey,
+            sender.publicKey,
+            gatekeeperNetwork.publicKey,
+            exchangeAmount,
+            senderMembershipTokenATA
+          );
+
+          const shouldFail = sendTransactionFromSender(
+            replaceAccount(instructions, metadata, fakeMetadata)
+          );
+          return expectProgramError(shouldFail, "MetadataAccountOwnerMismatch");
+        });
+
+        it("should not accept the metadata account of a different NFT", async () => {
+          sandbox.restore();
+          sandbox.stub(axios, "get").resolves({ data: metadataJson });
+          const response = await actions.mintNFT({
+            connection: nftMinterProvider.connection,
+            wallet: nftMinterProvider.wallet,
+            uri: dummyMetadataUri,
+            maxSupply: 1,
+          });
+          await nftMinterProvider.connection.confirmTransaction(response.txId);
+
+          const instructions = await exchange(
+            provider.connection,
+            program,
+            tokenGuardState.id,
+            sender.publicKey,
+            sender.publicKey,
+            gatekeeperNetwork.publicKey,
+            exchangeAmount,
+            senderMembershipTokenATA
+          );
+
+          // owned by the token metadata program, but not at the address derived from the mint
+          const shouldFail = sendTransactionFromSender(
+            replaceAccount(instructions, metadata, response.metadata)
+          );
+          return expectProgramError(shouldFail, "MetadataAccountMismatch");
+        });
+      });
+
+      context("Creator index", () => {
+        before("Mint an NFT whose verified creator is not the first creator", async () => {
+          // only the minter signs, so only the second creator is verified
+          sandbox.restore();
+          sandbox.stub(axios, "get").resolves({
+            data: {
+              ...metadataJson,
+              properties: {
+                ...metadataJson.properties,
+                creators: [
+                  {
+                    address: web3.Keypair.generate().publicKey.toBase58(),
+                    share: 50,
+                  },
+                  { address: nftMinter.publicKey.toBase58(), share: 50 },
+                ],
+              },
+            },
+          });
+          const response = await actions.mintNFT({
+            connection: nftMinterProvider.connection,
+            wallet: nftMinterProvider.wallet,
+            uri: dummyMetadataUri,
+            maxSupply: 1,
+          });
+          await nftMinterProvider.connection.confirmTransaction(response.txId);
+
+          // give it to the sender
+          const secondCreatorToken = new Token(
+            provider.connection,
+            response.mint,
+            TOKEN_PROGRAM_ID,
+            nftMinter
+          );
+          secondCreatorNftATA =
+            await secondCreatorToken.createAssociatedTokenAccount(
+              sender.publicKey
+            );
+          await checkBalanceAndSend(
+            secondCreatorToken,
+            nftMinter,
+            sender.publicKey
+          );
+        });
+
+        it("should let someone with an NFT whose verified creator is at the pinned position exchange", async () => {
+          tokenGuardState = await initialize(
+            program,
+            provider,
+            gatekeeperNetwork.publicKey,
+            recipient.publicKey,
+            undefined,
+            undefined,
+            undefined,
+            {
+              key: nftMinter.publicKey,
+              strategy: "NFT-Creator",
+              creatorIndex: 1,
+            }
+          );
+
+          const instructions = await exchange(
+            provider.connection,
+            program,
+            tokenGuardState.id,
+            sender.publicKey,
+            sender.publicKey,
+            gatekeeperNetwork.publicKey,
+            exchangeAmount,
+            secondCreatorNftATA
+          );
+
+          await sendTransactionFromSender(instructions);
+        });
+
+        it("should not let someone exchange if the creator is not at the pinned position", async () => {
+          tokenGuardState = await initialize(
+            program,
+            provider,
+            gatekeeperNetwork.publicKey,
+            recipient.publicKey,
+            undefined,
+            undefined,
+            undefined,
+            {
+              key: nftMinter.publicKey,
+              strategy: "NFT-Creator",
+              creatorIndex: 1,
+            }
+          );
+
+          await checkBalanceAndSend(nft, nftMinter, sender.publicKey);
+
+          // the NFT minter is the first and only creator
+          const instructions = await exchange(
+            provider.connection,
+            program,
+            tokenGuardState.id,
+            sender.publicKey,
+            sender.publicKey,
+            gatekeeperNetwork.publicKey,
+            exchangeAmount,
+            senderMembershipTokenATA
+          );
+
+          const shouldFail = sendTransactionFromSender(instructions);
+          return expectProgramError(shouldFail, "CreatorIndexOutOfRange");
+        });
+      });
+
+      context("Any creator strategy", () => {
+        it("should not let someone with an NFT from other creators exchange", async () => {
+          tokenGuardState = await initialize(
+            program,
+            provider,
+            gatekeeperNetwork.publicKey,
+            recipient.publicKey,
+            undefined,
+            undefined,
+            undefined,
+            {
+              key: web3.Keypair.generate().publicKey,
+              strategy: "NFT-Any-Creator",
+            }
+          );
+
+          await checkBalanceAndSend(nft, nftMinter, sender.publicKey);
+
+          const instructions = await exchange(
+            provider.connection,
+            program,
+            tokenGuardState.id,
+            sender.publicKey,
+            sender.publicKey,
+            gatekeeperNetwork.publicKey,
+            exchangeAmount,
+            senderMembershipTokenATA
+          );
+
+          const shouldFail = sendTransactionFromSender(instructions);
+          return expectProgramError(shouldFail, "MembershipTokenMismatch");
+        });
+
+        it("should let someone with an NFT from a verified creator exchange", async () => {
+          tokenGuardState = await initialize(
+            program,
+            provider,
+            gatekeeperNetwork.publicKey,
+            recipient.publicKey,
+            undefined,
+            undefined,
+            undefined,
+            {
+              key: nftMinter.publicKey,
+              strategy: "NFT-Any-Creator",
+            }
+          );
+
+          const instructions = await exchange(
+            provider.connection,
+            program,
+            tokenGuardState.id,
+            sender.publicKey,
+            sender.publicKey,
+            gatekeeperNetwork.publicKey,
+            exchangeAmount,
+            senderMembershipTokenATA
+          );
+
+          await sendTransactionFromSender(instructions);
+        });
+
+        it("should let someone with an NFT whose verified creator is not the first creator exchange", async () => {
+          // the tokenGuard of the previous test
+          const instructions = await exchange(
+            provider.connection,
+            program,
+            tokenGuardState.id,
+            sender.publicKey,
+            sender.publicKey,
+            gatekeeperNetwork.publicKey,
+            exchangeAmount,
+            secondCreatorNftATA
+          );
+
+          await sendTransactionFromSender(instructions);
+        });
       });
 
-      context("Creator strategy", () => {
-        it("should initialize a tokenGuard that requires presentation of an NFT", async () => {
+      context("NFT checks", () => {
+        it("should not accept a fungible token as an NFT", async () => {
+          tokenGuardState = await initialize(
+            program,
+            provider,
+            gatekeeperNetwork.publicKey,
+            recipient.publicKey,
+            undefined,
+            undefined,
+            undefined,
+            {
+              key: nftMinter.publicKey,
+              strategy: "NFT-Creator",
+            }
+          );
+
+          const fungibleToken = await Token.createMint(
+            provider.connection,
+            nftMinter,
+            nftMinter.publicKey,
+            null,
+            0,
+            TOKEN_PROGRAM_ID
+          );
+          const senderFungibleTokenATA =
+            await fungibleToken.createAssociatedTokenAccount(sender.publicKey);
+          await fungibleToken.mintTo(senderFungibleTokenATA, nftMinter, [], 2);
+
+          const instructions = await exchange(
+            provider.connection,
+            program,
+            tokenGuardState.id,
+            sender.publicKey,
+            sender.publicKey,
+            gatekeeperNetwork.publicKey,
+            exchangeAmount,
+            senderFungibleTokenATA
+          );
+
+          const shouldFail = sendTransactionFromSender(instructions);
+          return expectProgramError(shouldFail, "InvalidNftSupply");
+        });
+
+        it("should initialize a tokenGuard that requires a master edition", async () => {
           tokenGuardState = await initialize(
             program,
             provider,
@@ -642,72 +2598,73 @@ describe("token-guard", () => {
             {
               key: nftMinter.publicKey,
               strategy: "NFT-Creator",
+              requireMasterEdition: true,
             }
           );
         });
 
-        it("should not let someone without the membership token exchange", async () => {
-          const shouldFail = exchange(
+        it("should not accept a master edition account that belongs to the metadata", async () => {
+          await checkBalanceAndSend(nft, nftMinter, sender.publicKey);
+
+          const instructions = await exchange(
             provider.connection,
             program,
             tokenGuardState.id,
             sender.publicKey,
             sender.publicKey,
             gatekeeperNetwork.publicKey,
-            exchangeAmount
+            exchangeAmount,
+            senderMembershipTokenATA
+          );
+          const masterEdition = await programs.metadata.MasterEdition.getPDA(
+            mint
           );
 
-          return expect(shouldFail).to.be.rejectedWith(
-            /Membership token account not found/
+          // pass the metadata account (owned by the metadata program) as the master edition
+          const shouldFail = sendTransactionFromSender(
+            replaceAccount(instructions, masterEdition, metadata)
           );
+          return expectProgramError(shouldFail, "MasterEditionAccountMismatch");
         });
 
-        it("should not let someone with a membership token from an unverified creator exchange", async () => {
-          // the sender tries to mint their own NFT
-          // first they add their own address as a creator
-          // but keep the original address as the first creator
-          const bogusMetadataJson = {
-            ...metadataJson,
-            properties: {
-              ...metadataJson.properties,
-              creators: [
-                ...metadataJson.properties.creators,
-                {
-                  address: sender.publicKey.toBase58(),
-                  verified: false,
-                },
-              ],
-            },
-          };
-          sandbox.restore();
-          sandbox.stub(axios, "get").resolves({ data: bogusMetadataJson });
-
-          // next they mint their own NFT (To themselves)
-          await fund(sender.publicKey, 2_000_000_000);
-          const senderProvider = new Provider(
+        it("should let someone with a master edition NFT exchange", async () => {
+          const instructions = await exchange(
             provider.connection,
-            new anchor.Wallet(sender),
-            {}
+            program,
+            tokenGuardState.id,
+            sender.publicKey,
+            sender.publicKey,
+            gatekeeperNetwork.publicKey,
+            exchangeAmount,
+            senderMembershipTokenATA
           );
-          const response = await actions.mintNFT({
-            connection: senderProvider.connection,
-            wallet: senderProvider.wallet,
-            uri: dummyMetadataUri,
-            maxSupply: 1,
-          });
-          await senderProvider.connection.confirmTransaction(response.txId);
 
-          const bogusMint = response.mint;
+          await sendTransactionFromSender(instructions);
+        });
+      });
 
-          const bogusSenderMembershipTokenATA =
-            await Token.getAssociatedTokenAddress(
-              ASSOCIATED_TOKEN_PROGRAM_ID,
-              TOKEN_PROGRAM_ID,
-              bogusMint,
-              sender.publicKey
-            );
+      context("Collection strategy", () => {
+        const collection = web3.Keypair.generate().publicKey;
+
+        it("should initialize a tokenGuard that requires presentation of an NFT from a collection", async () => {
+          tokenGuardState = await initialize(
+            program,
+            provider,
+            gatekeeperNetwork.publicKey,
+            recipient.publicKey,
+            undefined,
+            undefined,
+            undefined,
+            {
+              key: collection,
+              strategy: "NFT-Collection",
+            }
+          );
+        });
+
+        it("should not let someone with an NFT that is not in a collection exchange", async () => {
+          await checkBalanceAndSend(nft, nftMinter, sender.publicKey);
 
-          // then they try to use that token to exchange
           const instructions = await exchange(
             provider.connection,
             program,
@@ -716,18 +2673,165 @@ describe("token-guard", () => {
             sender.publicKey,
             gatekeeperNetwork.publicKey,
             exchangeAmount,
-            bogusSenderMembershipTokenATA
+            senderMembershipTokenATA
           );
 
           const shouldFail = sendTransactionFromSender(instructions);
+          return expectProgramError(shouldFail, "NoCollection");
+        });
 
-          return expect(shouldFail).to.be.rejectedWith(
-            /Transaction simulation failed/
+        /**
+         * Instructions adding the NFT with the given metadata to a collection, and verifying it,
+         * signed by the NFT minter as the update authority of both.
+         * Built by hand, as the client library predates collections.
+         */
+        const addToCollectionInstructions = (
+          nftMetadata: web3.PublicKey,
+          collectionMint: web3.PublicKey,
+          collectionMetadata: web3.PublicKey,
+          collectionMasterEdition: web3.PublicKey
+        ) => {
+          const borshString = (value: string) => {
+            const length = Buffer.alloc(4);
+            length.writeUInt32LE(value.length);
+            return Buffer.concat([length, Buffer.from(value)]);
+          };
+          const sellerFeeBasisPoints = Buffer.alloc(2);
+          sellerFeeBasisPoints.writeUInt16LE(
+            metadataJson.seller_fee_basis_points
           );
-        });
+          const creatorCount = Buffer.alloc(4);
+          creatorCount.writeUInt32LE(1);
+
+          // UpdateMetadataAccountV2, keeping the data the NFT was minted with,
+          // and adding the (as yet unverified) collection
+          const updateData = Buffer.concat([
+            Buffer.from([15, 1]),
+            borshString(metadataJson.name),
+            borshString(metadataJson.symbol),
+            borshString(dummyMetadataUri),
+            sellerFeeBasisPoints,
+            Buffer.from([1]),
+            creatorCount,
+            nftMinter.publicKey.toBuffer(),
+            Buffer.from([1, 100]), // verified, share
+            Buffer.from([1, 0]), // collection, not verified
+            collectionMint.toBuffer(),
+            Buffer.from([0, 0, 0, 0]), // no uses or changes to the other properties
+          ]);
+
+          const metadataProgramId = programs.metadata.MetadataProgram.PUBKEY;
+          return [
+            new TransactionInstruction({
+              programId: metadataProgramId,
+              keys: [
+                { pubkey: nftMetadata, isSigner: false, isWritable: true },
+                {
+                  pubkey: nftMinter.publicKey,
+                  isSigner: true,
+                  isWritable: false,
+                },
+              ],
+              data: updateData,
+            }),
+            // VerifyCollection
+            new TransactionInstruction({
+              programId: metadataProgramId,
+              keys: [
+                { pubkey: nftMetadata, isSigner: false, isWritable: true },
+                // the collection authority, then the payer
+                {
+                  pubkey: nftMinter.publicKey,
+                  isSigner: true,
+                  isWritable: true,
+                },
+                {
+                  pubkey: nftMinter.publicKey,
+                  isSigner: true,
+                  isWritable: true,
+                },
+                { pubkey: collectionMint, isSigner: false, isWritable: false },
+                {
+                  pubkey: collectionMetadata,
+                  isSigner: false,
+                  isWritable: false,
+                },
+                {
+                  pubkey: collectionMasterEdition,
+                  isSigner: false,
+                  isWritable: false,
+                },
+              ],
+              data: Buffer.from([18]),
+            }),
+          ];
+        };
 
-        it("should let someone with the token exchange", async () => {
-          await checkBalanceAndSend(nft, nftMinter, sender.publicKey);
+        it("should let someone with an NFT from a verified collection exchange", async () => {
+          sandbox.restore();
+          sandbox.stub(axios, "get").resolves({ data: metadataJson });
+          const mintWithMinter = async () => {
+            const response = await actions.mintNFT({
+              connection: nftMinterProvider.connection,
+              wallet: nftMinterProvider.wallet,
+              uri: dummyMetadataUri,
+              maxSupply: 1,
+            });
+            await nftMinterProvider.connection.confirmTransaction(
+              response.txId
+            );
+            return response;
+          };
+          const collectionNft = await mintWithMinter();
+          const memberNft = await mintWithMinter();
+
+          await provider.send(
+            new web3.Transaction().add(
+              ...addToCollectionInstructions(
+                memberNft.metadata,
+                collectionNft.mint,
+                collectionNft.metadata,
+                collectionNft.edition
+              )
+            ),
+            [nftMinter]
+          );
+
+          // give the member NFT to the sender
+          const memberToken = new Token(
+            provider.connection,
+            memberNft.mint,
+            TOKEN_PROGRAM_ID,
+            nftMinter
+          );
+          const senderMemberTokenATA =
+            await memberToken.createAssociatedTokenAccount(sender.publicKey);
+          await memberToken.transfer(
+            await Token.getAssociatedTokenAddress(
+              ASSOCIATED_TOKEN_PROGRAM_ID,
+              TOKEN_PROGRAM_ID,
+              memberNft.mint,
+              nftMinter.publicKey
+            ),
+            senderMemberTokenATA,
+            nftMinter,
+            [],
+            1
+          );
+
+          tokenGuardState = await initialize(
+            program,
+            provider,
+            gatekeeperNetwork.publicKey,
+            recipient.publicKey,
+            undefined,
+            undefined,
+            undefined,
+            {
+              key: collectionNft.mint,
+              strategy: "NFT-Collection",
+            }
+          );
 
           const instructions = await exchange(
             provider.connection,
@@ -737,7 +2841,7 @@ describe("token-guard", () => {
             sender.publicKey,
             gatekeeperNetwork.publicKey,
             exchangeAmount,
-            senderMembershipTokenATA
+            senderMemberTokenATA
           );
 
           await sendTransactionFromSender(instructions);
@@ -821,5 +2925,201 @@ describe("token-guard", () => {
         });
       });
     });
+
+    context("Membership requirements", () => {
+      // this is often the same entity, but to avoid confusion, we alias here.
+      const membershipTokenMinter = recipient;
+      let daoToken: Token;
+      let passToken: Token;
+      let senderDaoTokenATA: web3.PublicKey;
+      let senderPassTokenATA: web3.PublicKey;
+
+      const createMembershipToken = async () => {
+        const token = await Token.createMint(
+          provider.connection,
+          membershipTokenMinter,
+          membershipTokenMinter.publicKey,
+          null,
+          0,
+          TOKEN_PROGRAM_ID
+        );
+        const senderATA = await token.createAssociatedTokenAccount(
+          sender.publicKey
+        );
+        return { token, senderATA };
+      };
+
+      it("initialises a tokenGuard that requires two membership tokens", async () => {
+        ({ token: daoToken, senderATA: senderDaoTokenATA } =
+          await createMembershipToken());
+        ({ token: passToken, senderATA: senderPassTokenATA } =
+          await createMembershipToken());
+
+        tokenGuardState = await initialize(
+          program,
+          provider,
+          gatekeeperNetwork.publicKey,
+          recipient.publicKey
+        );
+
+        await setMembershipRequirements(
+          program,
+          provider,
+          tokenGuardState.id,
+          [
+            { key: daoToken.publicKey, strategy: "SPL" },
+            { key: passToken.publicKey, strategy: "SPL" },
+          ]
+        );
+
+        const tokenGuardAccount = await program.account.tokenGuard.fetch(
+          tokenGuardState.id
+        );
+        expect(tokenGuardAccount.membershipRequirements).to.have.length(2);
+      });
+
+      it("should not let someone with only one of the membership tokens exchange", async () => {
+        await daoToken.mintTo(senderDaoTokenATA, membershipTokenMinter, [], 1);
+
+        const instructions = await exchange(
+          provider.connection,
+          program,
+          tokenGuardState.id,
+          sender.publicKey,
+          sender.publicKey,
+          gatekeeperNetwork.publicKey,
+          exchangeAmount,
+          undefined,
+          [senderDaoTokenATA, senderPassTokenATA]
+        );
+
+        const shouldFail = sendTransactionFromSender(instructions);
+        return expectProgramError(shouldFail, "NoMembershipToken");
+      });
+
+      it("should let someone with all of the membership tokens exchange", async () => {
+        await passToken.mintTo(
+          senderPassTokenATA,
+          membershipTokenMinter,
+          [],
+          1
+        );
+
+        const instructions = await exchange(
+          provider.connection,
+          program,
+          tokenGuardState.id,
+          sender.publicKey,
+          sender.publicKey,
+          gatekeeperNetwork.publicKey,
+          exchangeAmount,
+          undefined,
+          [senderDaoTokenATA, senderPassTokenATA]
+        );
+
+        await sendTransactionFromSender(instructions);
+      });
+    });
+
+    context("Membership alternatives", () => {
+      // this is often the same entity, but to avoid confusion, we alias here.
+      const membershipTokenMinter = recipient;
+      let partnerTokens: Token[];
+      let senderPartnerTokenATAs: web3.PublicKey[];
+      let senderUnlistedTokenATA: web3.PublicKey;
+
+      const createMembershipToken = async () => {
+        const token = await Token.createMint(
+          provider.connection,
+          membershipTokenMinter,
+          membershipTokenMinter.publicKey,
+          null,
+          0,
+          TOKEN_PROGRAM_ID
+        );
+        const senderATA = await token.createAssociatedTokenAccount(
+          sender.publicKey
+        );
+        await token.mintTo(senderATA, membershipTokenMinter, [], 1);
+        return { token, senderATA };
+      };
+
+      it("initialises a tokenGuard that accepts any of several membership tokens", async () => {
+        const partners = [
+          await createMembershipToken(),
+          await createMembershipToken(),
+        ];
+        partnerTokens = partners.map(({ token }) => token);
+        senderPartnerTokenATAs = partners.map(({ senderATA }) => senderATA);
+        ({ senderATA: senderUnlistedTokenATA } =
+          await createMembershipToken());
+
+        // the sender holds both partner tokens and an unlisted token,
+        // but not the membership token of the tokenGuard itself
+        tokenGuardState = await initialize(
+          program,
+          provider,
+          gatekeeperNetwork.publicKey,
+          recipient.publicKey,
+          undefined,
+          undefined,
+          undefined,
+          {
+            key: web3.Keypair.generate().publicKey,
+            strategy: "SPL",
+          }
+        );
+
+        await setMembershipAlternatives(
+          program,
+          provider,
+          tokenGuardState.id,
+          partnerTokens.map((token) => ({
+            key: token.publicKey,
+            strategy: "SPL",
+          }))
+        );
+
+        const tokenGuardAccount = await program.account.tokenGuard.fetch(
+          tokenGuardState.id
+        );
+        expect(tokenGuardAccount.membershipAlternatives).to.have.length(2);
+      });
+
+      it("should not let someone with an unlisted membership token exchange", async () => {
+        const instructions = await exchange(
+          provider.connection,
+          program,
+          tokenGuardState.id,
+          sender.publicKey,
+          sender.publicKey,
+          gatekeeperNetwork.publicKey,
+          exchangeAmount,
+          senderUnlistedTokenATA,
+          [],
+          1
+        );
+
+        const shouldFail = sendTransactionFromSender(instructions);
+        return expectProgramError(shouldFail, "MembershipTokenMismatch");
+      });
+
+      it("should let someone with any one of the membership alternatives exchange", async () => {
+        const instructions = await exchange(
+          provider.connection,
+          program,
+          tokenGuardState.id,
+          sender.publicKey,
+          sender.publicKey,
+          gatekeeperNetwork.publicKey,
+          exchangeAmount,
+          senderPartnerTokenATAs[1],
+          [],
+          1
+        );
+
+        await sendTransactionFromSender(instructions);
+      });
+    });
   });
 });